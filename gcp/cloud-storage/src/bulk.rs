@@ -0,0 +1,487 @@
+//! # プレフィックス単位のオブジェクトの一括コピーと移動
+//!
+//! 書き換え元のプレフィックスのオブジェクトを一覧して、それぞれを`rewrite_object`で書き換え先のバケットとプレフィックスにコピーする。
+//! 移動する場合は、コピーが完了した後に、コピーした世代と同じ世代の場合のみ書き換え元のオブジェクトを削除する。
+//!
+//! オブジェクトごとの結果は`TransferReport`に記録する。一部のオブジェクトが失敗しても、残りのオブジェクトの処理は続ける。
+//!
+//! 一覧しながら書き換えるため、同じバケットで一方のプレフィックスがもう一方を含む場合は、
+//! 書き換え先のオブジェクトが再び一覧されないように、書き換えを始める前にエラーを返す。
+use std::error::Error;
+use std::fmt::Display;
+
+use futures::{FutureExt as _, TryStreamExt as _};
+use google_cloud_gax::paginator::ItemPaginator as _;
+use google_cloud_storage::client::StorageControl;
+use google_cloud_storage::model::Object;
+
+use crate::RewriteDriver;
+use crate::rewrite;
+
+/// 既定の同時に書き換えるオブジェクトの最大数
+pub const DEFAULT_MAX_TRANSFERS: usize = 16;
+
+/// オブジェクトごとの結果
+#[derive(Debug)]
+pub enum TransferOutcome {
+    /// コピーした
+    Copied(Object),
+    /// コピーして、書き換え元を削除した
+    Moved(Object),
+    /// コピーに失敗した
+    CopyFailed(anyhow::Error),
+    /// コピーしたが、書き換え元の削除に失敗した
+    ///
+    /// 書き換え元が書き換え中に上書きされた場合も、このエラーになる。
+    DeleteFailed(Object, anyhow::Error),
+}
+
+/// 1つのオブジェクトの結果
+#[derive(Debug)]
+pub struct TransferResult {
+    /// 書き換え元のオブジェクト
+    pub source: Object,
+    /// 書き換え先のオブジェクト名
+    pub destination: String,
+    pub outcome: TransferOutcome,
+}
+
+impl TransferResult {
+    /// コピーに成功して、移動する場合は書き換え元の削除にも成功した場合に`true`を返す。
+    pub fn is_success(&self) -> bool {
+        matches!(
+            self.outcome,
+            TransferOutcome::Copied(_) | TransferOutcome::Moved(_)
+        )
+    }
+}
+
+/// 一括コピーの結果
+#[derive(Debug, Default)]
+pub struct TransferReport {
+    /// オブジェクトごとの結果（書き換え元のオブジェクト名の順）
+    pub results: Vec<TransferResult>,
+}
+
+impl TransferReport {
+    /// 失敗したオブジェクトの結果を返す。
+    pub fn failures(&self) -> impl Iterator<Item = &TransferResult> {
+        self.results.iter().filter(|r| !r.is_success())
+    }
+
+    /// 書き換えたバイト数の合計を返す。
+    pub fn bytes(&self) -> u64 {
+        self.results
+            .iter()
+            .filter(|r| r.is_success())
+            .map(|r| r.source.size as u64)
+            .sum()
+    }
+}
+
+/// 一括コピーのエラー
+#[derive(Debug)]
+pub enum BulkTransferError {
+    /// 同じバケットで、書き換え元と書き換え先のプレフィックスが重なっている
+    OverlappingPrefixes {
+        source_prefix: String,
+        destination_prefix: String,
+    },
+}
+
+impl Error for BulkTransferError {}
+
+impl Display for BulkTransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OverlappingPrefixes {
+                source_prefix,
+                destination_prefix,
+            } => write!(
+                f,
+                "the source prefix {source_prefix:?} and the destination prefix {destination_prefix:?} overlap in the same bucket"
+            ),
+        }
+    }
+}
+
+/// プレフィックス単位でオブジェクトをコピーまたは移動する。
+pub struct BulkTransfer<'a> {
+    control: &'a StorageControl,
+    source_bucket: String,
+    source_prefix: String,
+    destination_bucket: String,
+    destination_prefix: String,
+    storage_class: Option<String>,
+    delete_source: bool,
+    max_transfers: usize,
+}
+
+impl<'a> BulkTransfer<'a> {
+    /// バケットは`projects/_/buckets/{bucket_name}`形式で指定する。
+    ///
+    /// 書き換え先のオブジェクト名は、書き換え元のオブジェクト名の`source_prefix`を`destination_prefix`に置き換えた名前になる。
+    pub fn new(
+        control: &'a StorageControl,
+        source_bucket: impl Into<String>,
+        source_prefix: impl Into<String>,
+        destination_bucket: impl Into<String>,
+        destination_prefix: impl Into<String>,
+    ) -> Self {
+        Self {
+            control,
+            source_bucket: source_bucket.into(),
+            source_prefix: source_prefix.into(),
+            destination_bucket: destination_bucket.into(),
+            destination_prefix: destination_prefix.into(),
+            storage_class: None,
+            delete_source: false,
+            max_transfers: DEFAULT_MAX_TRANSFERS,
+        }
+    }
+
+    /// 書き換え先のストレージクラスを設定する。
+    ///
+    /// 設定しない場合は、書き換え先のバケットのデフォルトのストレージクラスになる。
+    pub fn with_storage_class(mut self, storage_class: impl Into<String>) -> Self {
+        self.storage_class = Some(storage_class.into());
+        self
+    }
+
+    /// コピーした後に書き換え元のオブジェクトを削除する。
+    pub fn with_move(mut self, delete_source: bool) -> Self {
+        self.delete_source = delete_source;
+        self
+    }
+
+    /// 同時に書き換えるオブジェクトの最大数を設定する。
+    pub fn with_max_transfers(mut self, max_transfers: usize) -> Self {
+        assert!(max_transfers > 0, "max transfers must be positive");
+        self.max_transfers = max_transfers;
+        self
+    }
+
+    /// 書き換え元のプレフィックスのオブジェクトをすべて書き換える。
+    ///
+    /// オブジェクトの一覧に失敗した場合はエラーを返す。その場合、書き換え中のオブジェクトの結果は返さない。
+    /// 同じバケットでプレフィックスが重なっている場合は、何も書き換えずに`BulkTransferError::OverlappingPrefixes`を返す。
+    pub async fn run(self) -> anyhow::Result<TransferReport> {
+        // 同じプレフィックスは書き換え先の名前が変わらないため、再び一覧されない
+        if self.source_bucket == self.destination_bucket
+            && self.source_prefix != self.destination_prefix
+            && (self.source_prefix.starts_with(&self.destination_prefix)
+                || self.destination_prefix.starts_with(&self.source_prefix))
+        {
+            return Err(BulkTransferError::OverlappingPrefixes {
+                source_prefix: self.source_prefix,
+                destination_prefix: self.destination_prefix,
+            }
+            .into());
+        }
+        let objects = self
+            .control
+            .list_objects()
+            .set_parent(&self.source_bucket)
+            .set_prefix(&self.source_prefix)
+            .by_item()
+            .into_stream();
+        let mut results: Vec<TransferResult> = objects
+            .map_ok(|object| self.transfer(object).map(Ok))
+            .try_buffer_unordered(self.max_transfers)
+            .try_collect()
+            .await?;
+        results.sort_by(|a, b| a.source.name.cmp(&b.source.name));
+        Ok(TransferReport { results })
+    }
+
+    async fn transfer(&self, source: Object) -> TransferResult {
+        let destination = format!(
+            "{}{}",
+            self.destination_prefix,
+            source
+                .name
+                .strip_prefix(&self.source_prefix)
+                .unwrap_or(&source.name)
+        );
+        let outcome = match self.rewrite(&source, &destination).await {
+            Ok(object) if self.delete_source => match self.delete(&source).await {
+                Ok(()) => TransferOutcome::Moved(object),
+                Err(e) => TransferOutcome::DeleteFailed(object, e),
+            },
+            Ok(object) => TransferOutcome::Copied(object),
+            Err(e) => TransferOutcome::CopyFailed(e),
+        };
+        TransferResult {
+            source,
+            destination,
+            outcome,
+        }
+    }
+
+    async fn rewrite(&self, source: &Object, destination: &str) -> anyhow::Result<Object> {
+        // 一覧した世代を書き換える
+        // ストレージクラスを変更する場合も、一覧したオブジェクトのメタデータは引き継ぐ
+        let mut builder = self
+            .control
+            .rewrite_object()
+            .set_source_bucket(&self.source_bucket)
+            .set_source_object(&source.name)
+            .set_source_generation(source.generation)
+            .set_destination_bucket(&self.destination_bucket)
+            .set_destination_name(destination);
+        if let Some(storage_class) = &self.storage_class {
+            builder = builder.set_destination(rewrite::destination(source, storage_class));
+        }
+        RewriteDriver::new(builder).run().await
+    }
+
+    async fn delete(&self, source: &Object) -> anyhow::Result<()> {
+        // 書き換えた世代から上書きされていない場合のみ削除する
+        self.control
+            .delete_object()
+            .set_bucket(&self.source_bucket)
+            .set_object(&source.name)
+            .set_if_generation_match(source.generation)
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BulkTransfer, BulkTransferError, TransferOutcome};
+    use gcs::client::StorageControl;
+    use gcs::model::{
+        DeleteObjectRequest, ListObjectsRequest, ListObjectsResponse, Object, RewriteObjectRequest,
+        RewriteResponse,
+    };
+    use google_cloud_gax as gax;
+    use google_cloud_storage as gcs;
+
+    mockall::mock! {
+        #[derive(Debug)]
+        StorageControl {}
+
+        impl gcs::stub::StorageControl for StorageControl {
+            async fn list_objects(&self, req: ListObjectsRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<ListObjectsResponse>>;
+            async fn rewrite_object(&self, req: RewriteObjectRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<RewriteResponse>>;
+            async fn delete_object(&self, req: DeleteObjectRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<()>>;
+        }
+    }
+
+    const SOURCE: &str = "projects/_/buckets/source";
+    const DESTINATION: &str = "projects/_/buckets/destination";
+
+    fn list(
+        req: ListObjectsRequest,
+        _options: gax::options::RequestOptions,
+    ) -> gax::Result<gax::response::Response<ListObjectsResponse>> {
+        assert_eq!(req.parent, SOURCE);
+        assert_eq!(req.prefix, "logs/");
+        let objects = ["logs/a.txt", "logs/b.txt", "logs/2025/c.txt"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| {
+                Object::new()
+                    .set_bucket(SOURCE)
+                    .set_name(name)
+                    .set_generation(100 + i as i64)
+                    .set_size(10)
+                    .set_content_type("text/plain")
+                    .set_metadata([("source", name)])
+            });
+        Ok(gax::response::Response::from(
+            ListObjectsResponse::new().set_objects(objects),
+        ))
+    }
+
+    // 書き換え先のオブジェクトを1回のリクエストで作成する
+    //
+    // 書き換え先を指定した場合は、指定したメタデータのみを設定する
+    fn rewrite(
+        req: RewriteObjectRequest,
+        _options: gax::options::RequestOptions,
+    ) -> gax::Result<gax::response::Response<RewriteResponse>> {
+        let object = req
+            .destination
+            .unwrap_or_default()
+            .set_bucket(&req.destination_bucket)
+            .set_name(&req.destination_name)
+            .set_generation(1)
+            .set_size(10);
+        Ok(gax::response::Response::from(
+            RewriteResponse::new()
+                .set_total_bytes_rewritten(10)
+                .set_object_size(10)
+                .set_done(true)
+                .set_resource(object),
+        ))
+    }
+
+    fn error(code: gax::error::rpc::Code) -> gax::error::Error {
+        gax::error::Error::service(
+            gax::error::rpc::Status::default()
+                .set_code(code)
+                .set_message("failed"),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_copy() -> anyhow::Result<()> {
+        let mut mock = MockStorageControl::new();
+        mock.expect_list_objects().times(1).returning(list);
+        mock.expect_rewrite_object()
+            .withf(|r, _| {
+                r.source_bucket == SOURCE
+                    && r.destination_bucket == DESTINATION
+                    && r.destination_name == format!("archive/{}", r.source_object)
+                    && r.source_generation >= 100
+            })
+            .times(3)
+            .returning(rewrite);
+        mock.expect_delete_object().never();
+        let control = StorageControl::from_stub(mock);
+
+        let report = BulkTransfer::new(&control, SOURCE, "logs/", DESTINATION, "archive/logs/")
+            .with_storage_class("NEARLINE")
+            .with_max_transfers(2)
+            .run()
+            .await?;
+        let names = report
+            .results
+            .iter()
+            .map(|r| r.destination.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "archive/logs/2025/c.txt",
+                "archive/logs/a.txt",
+                "archive/logs/b.txt"
+            ]
+        );
+        for result in &report.results {
+            match &result.outcome {
+                TransferOutcome::Copied(object) => assert_eq!(object.storage_class, "NEARLINE"),
+                outcome => panic!("unexpected outcome {outcome:?}"),
+            }
+        }
+        assert_eq!(report.failures().count(), 0);
+        assert_eq!(report.bytes(), 30);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_move() -> anyhow::Result<()> {
+        let mut mock = MockStorageControl::new();
+        mock.expect_list_objects().times(1).returning(list);
+        // `logs/a.txt`のコピーは失敗する
+        mock.expect_rewrite_object()
+            .withf(|r, _| r.source_object == "logs/a.txt")
+            .times(1)
+            .returning(|_, _| Err(error(gax::error::rpc::Code::PermissionDenied)));
+        mock.expect_rewrite_object()
+            .withf(|r, _| r.source_object != "logs/a.txt")
+            .times(2)
+            .returning(rewrite);
+        // `logs/b.txt`は書き換え中に上書きされたため、削除できない
+        mock.expect_delete_object()
+            .withf(|r, _| r.object == "logs/b.txt")
+            .times(1)
+            .returning(|_, _| Err(error(gax::error::rpc::Code::FailedPrecondition)));
+        mock.expect_delete_object()
+            .withf(|r, _| {
+                r.bucket == SOURCE
+                    && r.object == "logs/2025/c.txt"
+                    && r.if_generation_match == Some(102)
+            })
+            .times(1)
+            .returning(|_, _| Ok(gax::response::Response::from(())));
+        let control = StorageControl::from_stub(mock);
+
+        let report = BulkTransfer::new(&control, SOURCE, "logs/", DESTINATION, "logs/")
+            .with_move(true)
+            .run()
+            .await?;
+        let outcomes = report
+            .results
+            .iter()
+            .map(|r| (r.source.name.as_str(), &r.outcome))
+            .collect::<Vec<_>>();
+        assert!(
+            matches!(
+                outcomes.as_slice(),
+                [
+                    ("logs/2025/c.txt", TransferOutcome::Moved(_)),
+                    ("logs/a.txt", TransferOutcome::CopyFailed(_)),
+                    ("logs/b.txt", TransferOutcome::DeleteFailed(_, _)),
+                ]
+            ),
+            "{outcomes:?}"
+        );
+        assert_eq!(report.failures().count(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_storage_class_keeps_metadata() -> anyhow::Result<()> {
+        for delete_source in [false, true] {
+            let mut mock = MockStorageControl::new();
+            mock.expect_list_objects().times(1).returning(list);
+            mock.expect_rewrite_object().times(3).returning(rewrite);
+            mock.expect_delete_object()
+                .times(if delete_source { 3 } else { 0 })
+                .returning(|_, _| Ok(gax::response::Response::from(())));
+            let control = StorageControl::from_stub(mock);
+
+            let report = BulkTransfer::new(&control, SOURCE, "logs/", DESTINATION, "archive/")
+                .with_storage_class("COLDLINE")
+                .with_move(delete_source)
+                .run()
+                .await?;
+            for result in &report.results {
+                let object = match &result.outcome {
+                    TransferOutcome::Copied(object) if !delete_source => object,
+                    TransferOutcome::Moved(object) if delete_source => object,
+                    outcome => panic!("unexpected outcome {outcome:?}"),
+                };
+                assert_eq!(object.storage_class, "COLDLINE");
+                assert_eq!(object.content_type, "text/plain");
+                assert_eq!(
+                    object.metadata.get("source"),
+                    Some(&result.source.name),
+                    "{object:?}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_prefixes() -> anyhow::Result<()> {
+        let mut mock = MockStorageControl::new();
+        mock.expect_list_objects().never();
+        mock.expect_rewrite_object().never();
+        let control = StorageControl::from_stub(mock);
+
+        for (source, destination) in [
+            ("logs/", "logs/archive/"),
+            ("logs/2025/", "logs/"),
+            ("", "a/"),
+        ] {
+            let err = BulkTransfer::new(&control, SOURCE, source, SOURCE, destination)
+                .run()
+                .await
+                .expect_err("the prefixes overlap");
+            assert!(
+                matches!(
+                    err.downcast_ref::<BulkTransferError>(),
+                    Some(BulkTransferError::OverlappingPrefixes { .. })
+                ),
+                "{err:?}"
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+//! # アップロードのキャンセル
+//!
+//! アップロードを中止するには、`StreamingSource`がエラーを返す必要がある。
+//! `CancellableSource`はソースをラップして、`CancellationToken`がキャンセルされた時に`CancelError::Cancelled`を返す。
+//!
+//! アップロードは`err.is_serialization()`が`true`のエラーで失敗し、`source()`を`CancelError`にダウンキャストできる。
+//! `CancelError`はラップしているソースの型によらないため、どのソースでも同じ型でダウンキャストできる。
+//! キャンセルはソースを読み込んでいる間のみ有効で、ソースを読み終えた後のアップロードの完了処理は中止しない。
+use std::error::Error;
+use std::fmt::Display;
+
+use bytes::Bytes;
+use google_cloud_storage::streaming_source::{Seek, SizeHint, StreamingSource};
+use tokio_util::sync::CancellationToken;
+
+/// トークンがキャンセルされた時にエラーを返すソース
+#[derive(Debug)]
+pub struct CancellableSource<S> {
+    inner: S,
+    token: CancellationToken,
+}
+
+impl<S> CancellableSource<S> {
+    pub fn new(inner: S, token: CancellationToken) -> Self {
+        Self { inner, token }
+    }
+
+    /// ラップしているソースを返す。
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> StreamingSource for CancellableSource<S>
+where
+    S: StreamingSource + Send + Sync,
+{
+    type Error = CancelError;
+
+    async fn next(&mut self) -> Option<Result<Bytes, Self::Error>> {
+        tokio::select! {
+            biased;
+            _ = self.token.cancelled() => Some(Err(CancelError::Cancelled)),
+            chunk = self.inner.next() => chunk.map(|r| r.map_err(CancelError::boxed)),
+        }
+    }
+
+    async fn size_hint(&self) -> Result<SizeHint, Self::Error> {
+        self.inner.size_hint().await.map_err(CancelError::boxed)
+    }
+}
+
+impl<S> Seek for CancellableSource<S>
+where
+    S: Seek + Send + Sync,
+{
+    type Error = CancelError;
+
+    async fn seek(&mut self, offset: u64) -> Result<(), Self::Error> {
+        if self.token.is_cancelled() {
+            return Err(CancelError::Cancelled);
+        }
+        self.inner.seek(offset).await.map_err(CancelError::boxed)
+    }
+}
+
+/// `CancellableSource`のエラー
+///
+/// ラップしているソースのエラーは`source()`からダウンキャストできる。
+#[derive(Debug)]
+pub enum CancelError {
+    /// トークンがキャンセルされた
+    Cancelled,
+    /// ラップしているソースがエラーを返した
+    Source(Box<dyn Error + Send + Sync>),
+}
+
+impl CancelError {
+    /// キャンセルされた場合は`true`を返す。
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, Self::Cancelled)
+    }
+
+    fn boxed<E>(e: E) -> Self
+    where
+        E: Error + Send + Sync + 'static,
+    {
+        Self::Source(Box::new(e))
+    }
+}
+
+impl Error for CancelError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Cancelled => None,
+            Self::Source(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+impl Display for CancelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cancelled => write!(f, "the upload was cancelled"),
+            Self::Source(e) => write!(f, "the source failed: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CancelError, CancellableSource};
+    use crate::{FakeStorage, QueueError, queue};
+    use bytes::Bytes;
+    use gcs::client::Storage;
+    use gcs::streaming_source::Payload;
+    use google_cloud_storage as gcs;
+    use std::error::Error as _;
+    use tokio_util::sync::CancellationToken;
+
+    const BUCKET: &str = "projects/_/buckets/my-bucket";
+
+    #[tokio::test]
+    async fn test_cancel() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        // 送信側を保持しているため、キャンセルしなければアップロードは終わらない
+        let (sender, source) = queue(1024);
+        let token = CancellationToken::new();
+        let upload = tokio::spawn(
+            client
+                .write_object(
+                    BUCKET,
+                    "my-object",
+                    CancellableSource::new(source, token.clone()),
+                )
+                .send_buffered(),
+        );
+        sender.send(Bytes::from_static(b"partial")).await?;
+        token.cancel();
+
+        let err = upload.await?.expect_err("the upload was cancelled");
+        assert!(err.is_serialization(), "{err:?}");
+        assert!(
+            err.source()
+                .and_then(|e| e.downcast_ref::<CancelError>())
+                .is_some_and(CancelError::is_cancelled),
+            "{err:?}"
+        );
+        assert!(fake.object(BUCKET, "my-object").is_none());
+        drop(sender);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancel_unbuffered() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        // ソースの型が異なっても、同じ`CancelError`にダウンキャストできる
+        let token = CancellationToken::new();
+        token.cancel();
+        let payload = Payload::from(Bytes::from_static(b"hello"));
+        let err = client
+            .write_object(BUCKET, "my-object", CancellableSource::new(payload, token))
+            .send_unbuffered()
+            .await
+            .expect_err("the upload was cancelled");
+        assert!(err.is_serialization(), "{err:?}");
+        assert!(
+            err.source()
+                .and_then(|e| e.downcast_ref::<CancelError>())
+                .is_some_and(CancelError::is_cancelled),
+            "{err:?}"
+        );
+        assert!(fake.object(BUCKET, "my-object").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_not_cancelled() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        let (sender, source) = queue(1024);
+        let upload = tokio::spawn(
+            client
+                .write_object(
+                    BUCKET,
+                    "my-object",
+                    CancellableSource::new(source, CancellationToken::new()),
+                )
+                .send_buffered(),
+        );
+        sender.send(Bytes::from_static(b"hello")).await?;
+        drop(sender);
+        let object = upload.await??;
+        assert_eq!(object.size, 5);
+
+        // ラップしたソースのエラーは`CancelError::Source`になる
+        let (sender, source) = queue(1024);
+        let upload = tokio::spawn(
+            client
+                .write_object(
+                    BUCKET,
+                    "failed",
+                    CancellableSource::new(source, CancellationToken::new()),
+                )
+                .send_buffered(),
+        );
+        sender.abort("the producer failed");
+        let err = upload.await?.expect_err("the producer failed");
+        let source = err
+            .source()
+            .and_then(|e| e.downcast_ref::<CancelError>())
+            .expect("the source is CancelError");
+        assert!(!source.is_cancelled(), "{source:?}");
+        assert!(
+            matches!(
+                source.source().and_then(|e| e.downcast_ref::<QueueError>()),
+                Some(QueueError::Aborted(_))
+            ),
+            "{source:?}"
+        );
+        Ok(())
+    }
+}
@@ -1,51 +1,87 @@
-//! # 分割ダウンロードのストライプサイズ
-//!
-//! | 推奨されるストライプサイズ| 理由 |
-//! | --- | --- |
-//! | 8MiB ($8,388,608$ Byte) | 多くのクラウドストレージ環境でバランスの取れた良いパフォーマンスを示す標準的なサイズで、APIのオーバーヘッドを十分に吸収 |
-//! | 16MiB ($16,777,216$ Byte) | 比較的安定した高速ネットワーク接続がある場合に推奨 |
-//! | 32MiB ($33,554,432$ Byte) | 非常に高速で低遅延なネットワーク環境（例：GCP内のVM間）での最大スループットを目指す場合 |
 use google_cloud_storage::client::{Storage, StorageControl};
+use google_cloud_storage::model::Object;
 use google_cloud_storage::model::compose_object_request::SourceObject;
-use google_cloud_storage::model::{Bucket, Object};
-use google_cloud_storage::model_ext::ReadRange;
-use tokio::io::{AsyncSeekExt as _, AsyncWriteExt as _};
 
-use cloud_storage::{PROJECT_ID, create_bucket};
+use cloud_storage::{PROJECT_ID, ParallelDownloader, ParallelUploader, TempBucket, Verification};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let client = Storage::builder().build().await?;
     let control = StorageControl::builder().build().await?;
 
-    let bucket_name = format!("my-bucket-{}", uuid::Uuid::new_v4());
     let file_sizes = [2, 4 /*, 8, 16, 32*/];
 
-    // バケットを作成
-    let bucket = create_bucket(&control, PROJECT_ID, &bucket_name).await?;
-    println!("bucket successfully created {bucket:?}");
+    // 一時的なバケットを作成
+    let bucket = TempBucket::create(&control, PROJECT_ID).await?;
+    println!("bucket successfully created {:?}", bucket.bucket());
 
     // 巨大なファイルを作成
-    let filenames = seed(&client, &control, &bucket.name, &file_sizes).await?;
+    let filenames = seed(&client, &control, bucket.name(), &file_sizes).await?;
 
     // 巨大なファイルをダウンロード
     let destination = format!("outputs/{}", filenames.last().unwrap());
-    download(
+    let downloader = ParallelDownloader::new(&client, bucket.name(), filenames.last().unwrap())
+        .with_auto_tuning(true)
+        .with_journal(true)
+        .with_verification(Verification::Crc32c);
+
+    // 1秒ごとに進捗を表示
+    let mut progress = downloader.subscribe();
+    tokio::spawn(async move {
+        while progress.has_changed().is_ok() {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            let p = progress.borrow_and_update().clone();
+            println!(
+                "{}/{} MiB, {}/{} stripes, {:.2} MiB/s, ETA {:?}",
+                p.bytes / (1024 * 1024),
+                p.total_bytes / (1024 * 1024),
+                p.stripes,
+                p.total_stripes,
+                p.throughput / (1024.0 * 1024.0),
+                p.eta
+            );
+        }
+    });
+
+    let report = downloader.download(&destination).await?;
+    println!(
+        "Completed {:.2} MiB download in {:?}, using {} stripes, effective bandwidth = {:.2} MiB/s",
+        report.bytes as f64 / (1024.0 * 1024.0),
+        report.elapsed,
+        report.stripes,
+        report.bandwidth()
+    );
+    if let Some(tuning) = report.tuning {
+        println!(
+            "Selected stripe size = {} MiB, max in flight = {}",
+            tuning.stripe_size / (1024 * 1024),
+            tuning.max_in_flight
+        );
+    }
+
+    // ダウンロードしたファイルを並行複合アップロード
+    let report = ParallelUploader::new(
         &client,
         &control,
-        &bucket,
-        filenames.last().unwrap(),
-        8 * 1024 * 1024,
         &destination,
+        bucket.name(),
+        format!("{}.copy", filenames.last().unwrap()),
     )
+    .upload()
     .await?;
+    println!(
+        "Uploaded object {} ({:.2} MiB) in {:?}, using {} parts",
+        report.object.name,
+        report.bytes as f64 / (1024.0 * 1024.0),
+        report.elapsed,
+        report.parts
+    );
+    if !report.orphaned.is_empty() {
+        println!("Failed to delete temporary objects {:?}", report.orphaned);
+    }
 
-    //// バケットを削除
-    //control
-    //    .delete_bucket()
-    //    .set_name(&bucket.name)
-    //    .send()
-    //    .await?;
+    // 作成したオブジェクトをすべて削除してから、バケットを削除
+    bucket.delete().await?;
 
     Ok(())
 }
@@ -130,76 +166,3 @@ async fn seed(
     }
     Ok(filenames)
 }
-
-// 巨大なファイルをダウンロード
-async fn download(
-    client: &Storage,
-    control: &StorageControl,
-    bucket: &Bucket,
-    filename: &str,
-    stripe_size: usize,
-    destination: &str,
-) -> anyhow::Result<()> {
-    let metadata = control
-        .get_object()
-        .set_bucket(&bucket.name)
-        .set_object(filename)
-        .send()
-        .await?;
-
-    let file = tokio::fs::File::create(destination).await?;
-    let start = std::time::Instant::now();
-
-    let size = metadata.size as u64;
-    let limit = stripe_size as u64;
-    let count = size / limit;
-    let mut stripes = (0..count)
-        .map(|i| write_stripe(client.clone(), &file, i * limit, limit, &metadata))
-        .collect::<Vec<_>>();
-    // ダウンロードするファイルについて、ファイル末尾にあるストライプサイズ未満の残りのデータをダウンロードして、ファイルに書き込み
-    if !size.is_multiple_of(limit) {
-        stripes.push(write_stripe(
-            client.clone(),
-            &file,
-            count * limit,
-            limit,
-            &metadata,
-        ));
-    }
-
-    futures::future::join_all(stripes)
-        .await
-        .into_iter()
-        .collect::<anyhow::Result<Vec<_>>>()?;
-
-    let elapsed = start.elapsed();
-    let mib = metadata.size as f64 / (1024.0 * 1024.0);
-    let bw = mib / elapsed.as_secs_f64();
-    println!(
-        "Completed {mib:.2} MiB download in {elapsed:?}, using {count} stripes, effective bandwidth = {bw:.2} MiB/s"
-    );
-
-    Ok(())
-}
-
-async fn write_stripe(
-    client: Storage,
-    file: &tokio::fs::File,
-    offset: u64,
-    limit: u64,
-    metadata: &Object,
-) -> anyhow::Result<()> {
-    let mut writer = file.try_clone().await?;
-    writer.seek(std::io::SeekFrom::Start(offset)).await?;
-    let mut reader = client
-        .read_object(&metadata.bucket, &metadata.name)
-        .set_generation(metadata.generation)
-        .set_read_range(ReadRange::segment(offset, limit))
-        .send()
-        .await?;
-    while let Some(b) = reader.next().await.transpose()? {
-        writer.write_all(&b).await?;
-    }
-
-    Ok(())
-}
@@ -0,0 +1,1670 @@
+//! # 巨大なオブジェクトの分割ダウンロード
+//!
+//! オブジェクトを固定サイズのストライプに分割して、それぞれのストライプを並行してダウンロードする。
+//!
+//! ## 分割ダウンロードのストライプサイズ
+//!
+//! | 推奨されるストライプサイズ| 理由 |
+//! | --- | --- |
+//! | 8MiB ($8,388,608$ Byte) | 多くのクラウドストレージ環境でバランスの取れた良いパフォーマンスを示す標準的なサイズで、APIのオーバーヘッドを十分に吸収 |
+//! | 16MiB ($16,777,216$ Byte) | 比較的安定した高速ネットワーク接続がある場合に推奨 |
+//! | 32MiB ($33,554,432$ Byte) | 非常に高速で低遅延なネットワーク環境（例：GCP内のVM間）での最大スループットを目指す場合 |
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use futures::{StreamExt as _, TryStreamExt as _};
+use google_cloud_gax::backoff_policy::BackoffPolicy;
+use google_cloud_gax::error::rpc::Code;
+use google_cloud_gax::exponential_backoff::ExponentialBackoff;
+use google_cloud_gax::retry_policy::{RetryPolicy, RetryPolicyExt as _};
+use google_cloud_gax::retry_result::RetryResult;
+use google_cloud_gax::retry_state::RetryState;
+use google_cloud_storage as gcs;
+use google_cloud_storage::client::Storage;
+use google_cloud_storage::model_ext::{ObjectHighlights, ReadRange};
+use google_cloud_storage::read_object::ReadObjectResponse;
+use google_cloud_storage::retry_policy::RetryableErrors;
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::sync::watch;
+
+use crate::journal::Journal;
+
+/// 既定のストライプサイズ（8MiB）
+pub const DEFAULT_STRIPE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// 既定の同時にダウンロードするストライプの最大数
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+
+/// 分割ダウンロードの結果
+#[derive(Clone, Debug)]
+pub struct DownloadReport {
+    /// ダウンロードしたバイト数
+    pub bytes: u64,
+    /// ダウンロードにかかった時間
+    pub elapsed: Duration,
+    /// ストライプの数
+    pub stripes: usize,
+    /// 前回のダウンロードで完了していたため、ダウンロードしなかったストライプの数
+    pub resumed: usize,
+    /// ストライプごとの再試行した回数
+    pub retries: Vec<u32>,
+    /// 自動調整で選択した設定
+    pub tuning: Option<Tuning>,
+}
+
+/// ダウンロードの進捗
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DownloadProgress {
+    /// ダウンロードが完了したバイト数
+    pub bytes: u64,
+    /// オブジェクトのサイズ
+    pub total_bytes: u64,
+    /// ダウンロードが完了したストライプの数
+    pub stripes: usize,
+    /// ストライプの数
+    ///
+    /// ストライプサイズを自動で選択する場合は、最初のストライプのダウンロードが完了するまで0である。
+    pub total_stripes: usize,
+    /// ダウンロードを開始してからのスループット（バイト/秒）
+    pub throughput: f64,
+    /// 現在のスループットから推定した残り時間
+    pub eta: Option<Duration>,
+}
+
+/// 最初のストライプの測定結果から選択したダウンロードの設定
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    /// 2番目以降のストライプのサイズ
+    pub stripe_size: u64,
+    /// 同時にダウンロードするストライプの最大数
+    pub max_in_flight: usize,
+    /// 最初のストライプで測定した帯域幅（バイト/秒）
+    pub bandwidth: f64,
+    /// 最初のストライプのレスポンスを受け取るまでの時間
+    pub latency: Duration,
+}
+
+/// ダウンロードしたファイルの検証方法
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Verification {
+    /// 検証しない
+    #[default]
+    None,
+    /// ストライプごとに計算したCRC32Cを結合して検証する。
+    ///
+    /// オブジェクトにCRC32Cがない場合は、MD5で検証する。
+    Crc32c,
+    /// MD5で検証する。
+    ///
+    /// ファイルにダウンロードする場合は、ダウンロードが完了した後にファイルを再度読み込んで計算する。
+    Md5,
+}
+
+/// 分割ダウンロードのエラー
+#[derive(Debug)]
+pub enum DownloadError {
+    /// ダウンロードしたファイルのCRC32Cが、オブジェクトのCRC32Cと一致しない
+    Crc32cMismatch { expected: u32, actual: u32 },
+    /// ダウンロードしたファイルのMD5が、オブジェクトのMD5と一致しない
+    Md5Mismatch { expected: Vec<u8>, actual: Vec<u8> },
+    /// オブジェクトに検証に使用できるチェックサムがない
+    ChecksumUnavailable,
+    /// ダウンロード中にオブジェクトが上書きまたは削除されたため、最初のストライプと同じ世代を読み込めない
+    ObjectChanged { expected_generation: i64 },
+}
+
+impl Error for DownloadError {}
+
+impl Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Crc32cMismatch { expected, actual } => write!(
+                f,
+                "CRC32C mismatch, expected={expected:#010x}, actual={actual:#010x}"
+            ),
+            Self::Md5Mismatch { expected, actual } => {
+                write!(
+                    f,
+                    "MD5 mismatch, expected={expected:02x?}, actual={actual:02x?}"
+                )
+            }
+            Self::ChecksumUnavailable => write!(f, "the object has no usable checksum"),
+            Self::ObjectChanged {
+                expected_generation,
+            } => write!(
+                f,
+                "the object was overwritten or deleted during the download, expected generation={expected_generation}"
+            ),
+        }
+    }
+}
+
+impl DownloadReport {
+    /// 実効帯域幅（MiB/s）
+    pub fn bandwidth(&self) -> f64 {
+        let mib = self.bytes as f64 / (1024.0 * 1024.0);
+        mib / self.elapsed.as_secs_f64()
+    }
+}
+
+/// オブジェクトをストライプに分割して、並行してダウンロードする。
+///
+/// ダウンロード先は、ファイル（`download`）、メモリ上のバッファー（`download_to_bytes`）、
+/// 先頭から順に書き込む`AsyncWrite`（`download_to_writer`）から選択できる。
+///
+/// `gcs::stub::Storage`についてジェネリックであるため、テストではGCSの代わりにメモリ上のスタブを使用できる。
+pub struct ParallelDownloader<'a, T>
+where
+    T: gcs::stub::Storage + 'static,
+{
+    client: &'a Storage<T>,
+    bucket: String,
+    object: String,
+    stripe_size: u64,
+    max_in_flight: usize,
+    auto_tuning: bool,
+    journal: bool,
+    verification: Verification,
+    retry_policy: Arc<dyn RetryPolicy>,
+    backoff_policy: Arc<dyn BackoffPolicy>,
+    progress: watch::Sender<DownloadProgress>,
+}
+
+impl<'a, T> ParallelDownloader<'a, T>
+where
+    T: gcs::stub::Storage + 'static,
+{
+    /// `bucket`は`projects/_/buckets/{bucket_name}`形式のバケット名を指定する。
+    pub fn new(
+        client: &'a Storage<T>,
+        bucket: impl Into<String>,
+        object: impl Into<String>,
+    ) -> Self {
+        Self {
+            client,
+            bucket: bucket.into(),
+            object: object.into(),
+            stripe_size: DEFAULT_STRIPE_SIZE,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            auto_tuning: false,
+            journal: false,
+            verification: Verification::None,
+            retry_policy: Arc::new(RetryableErrors.with_attempt_limit(5)),
+            backoff_policy: Arc::new(ExponentialBackoff::default()),
+            progress: watch::Sender::new(DownloadProgress::default()),
+        }
+    }
+
+    /// ストライプサイズを設定する。
+    pub fn with_stripe_size(mut self, stripe_size: u64) -> Self {
+        assert!(stripe_size > 0, "stripe size must be positive");
+        self.stripe_size = stripe_size;
+        self
+    }
+
+    /// 同時にダウンロードするストライプの最大数を設定する。
+    ///
+    /// 巨大なオブジェクトでも、リクエストとファイルハンドルの数はこの値までに制限される。
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        assert!(max_in_flight > 0, "max in flight must be positive");
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// ストライプサイズと同時にダウンロードするストライプの最大数を自動で選択する。
+    ///
+    /// 最初のストライプを`with_stripe_size`で設定したサイズでダウンロードして帯域幅とレイテンシを測定し、
+    /// 残りのストライプのサイズと最大数を選択する。選択した設定は`DownloadReport::tuning`で確認できる。
+    pub fn with_auto_tuning(mut self, auto_tuning: bool) -> Self {
+        self.auto_tuning = auto_tuning;
+        self
+    }
+
+    /// 進捗をジャーナルに記録して、中断したダウンロードを再開できるようにする。
+    ///
+    /// ファイルにダウンロードする場合のみ使用する。
+    /// ジャーナルはダウンロード先のファイルの隣に作成され、ダウンロードが完了すると削除される。
+    /// ジャーナルが残っている場合は、完了していないストライプのみをダウンロードする。
+    /// ただし、オブジェクトの世代がジャーナルに記録された世代と異なる場合は、
+    /// 古いジャーナルを破棄して最初からダウンロードし直す。
+    pub fn with_journal(mut self, journal: bool) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    /// ストライプごとの再試行ポリシーを設定する。
+    ///
+    /// 既定では、`RetryableErrors`を5回まで再試行する。
+    pub fn with_retry_policy<P>(mut self, policy: P) -> Self
+    where
+        P: RetryPolicy + 'static,
+    {
+        self.retry_policy = Arc::new(policy);
+        self
+    }
+
+    /// ストライプを再試行するまでの待機時間のポリシーを設定する。
+    pub fn with_backoff_policy<P>(mut self, policy: P) -> Self
+    where
+        P: BackoffPolicy + 'static,
+    {
+        self.backoff_policy = Arc::new(policy);
+        self
+    }
+
+    /// ダウンロードしたデータを、オブジェクトのチェックサムと比較する方法を設定する。
+    pub fn with_verification(mut self, verification: Verification) -> Self {
+        self.verification = verification;
+        self
+    }
+
+    /// ダウンロードの進捗を受け取るレシーバーを返す。
+    ///
+    /// 進捗はストライプのデータを受け取るたびに更新される。
+    pub fn subscribe(&self) -> watch::Receiver<DownloadProgress> {
+        self.progress.subscribe()
+    }
+
+    /// オブジェクトをファイルにダウンロードする。
+    pub async fn download(self, destination: impl AsRef<Path>) -> anyhow::Result<DownloadReport> {
+        let (report, _) = self.run(Target::File(destination.as_ref())).await?;
+        Ok(report)
+    }
+
+    /// オブジェクトをメモリ上のバッファーにダウンロードする。
+    ///
+    /// バッファーはオブジェクトのサイズで確保され、それぞれのストライプはバッファーの対応する位置に直接書き込まれる。
+    /// `Vec<u8>`が必要な場合は、`Vec::from`で変換できる。
+    pub async fn download_to_bytes(self) -> anyhow::Result<(BytesMut, DownloadReport)> {
+        let (report, buffer) = self.run(Target::Buffer).await?;
+        Ok((buffer.unwrap_or_default(), report))
+    }
+
+    /// オブジェクトを先頭から順に`writer`に書き込む。
+    ///
+    /// ストライプは並行してダウンロードして、オフセットの順に並べ替えてから書き込むため、
+    /// 伸長器のようなシークできない書き込み先にも使用できる。
+    /// メモリに保持するストライプは、同時にダウンロードするストライプの最大数までである。
+    pub async fn download_to_writer<W>(self, writer: &mut W) -> anyhow::Result<DownloadReport>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let (report, _) = self.run(Target::Writer(writer)).await?;
+        Ok(report)
+    }
+
+    // オブジェクトをダウンロードして、バッファーにダウンロードした場合はバッファーを返す
+    async fn run(&self, target: Target<'_>) -> anyhow::Result<(DownloadReport, Option<BytesMut>)> {
+        let start = Instant::now();
+        // ファイルと書き込み先のどちらも指定されていない場合は、バッファーにダウンロード
+        let (destination, writer) = match target {
+            Target::File(destination) => (Some(destination), None),
+            Target::Buffer => (None, None),
+            Target::Writer(writer) => (None, Some(writer)),
+        };
+
+        // ジャーナルとダウンロード先のファイルが残っている場合は、前回のダウンロードを再開
+        let resume = match destination {
+            Some(destination) if self.journal && tokio::fs::try_exists(destination).await? => {
+                Journal::load(destination).await?
+            }
+            _ => None,
+        };
+        let probe_size = resume.as_ref().map_or(self.stripe_size, |s| s.probe_size);
+        let file = match destination {
+            Some(destination) if resume.is_some() => Some(
+                tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .open(destination)
+                    .await?,
+            ),
+            Some(destination) => Some(tokio::fs::File::create(destination).await?),
+            None => None,
+        };
+
+        // 最初のストライプを読み込むときに、オブジェクトのサイズと世代を取得
+        let probe_start = Instant::now();
+        let (reader, probe_retries) = self
+            .retry(|| async {
+                self.read_stripe(0, probe_size, None)
+                    .await
+                    .map_err(anyhow::Error::from)
+            })
+            .await?;
+        let latency = probe_start.elapsed();
+        let metadata = reader.object();
+        // オブジェクトが上書きされていた場合は、古いジャーナルを破棄して最初からダウンロード
+        // ジャーナルは`Journal::create`で作り直す
+        let resume = resume.filter(|s| s.generation == metadata.generation);
+        let size = metadata.size as u64;
+        if let Some(file) = &file {
+            file.set_len(size).await?;
+        }
+        let buffer = (destination.is_none() && writer.is_none())
+            .then(|| std::sync::Mutex::new(BytesMut::zeroed(size as usize)));
+        let sink = match (destination, &buffer) {
+            (Some(destination), _) => StripeSink::File(destination),
+            (None, Some(buffer)) => StripeSink::Buffer(buffer),
+            (None, None) => StripeSink::Memory,
+        };
+
+        // 書き込み先には再度読み込めないため、検証に必要な場合は書き込むときにMD5を計算
+        let has_crc32c = metadata
+            .checksums
+            .as_ref()
+            .is_some_and(|c| c.crc32c.is_some());
+        let needs_md5 = match self.verification {
+            Verification::None => false,
+            Verification::Crc32c => !has_crc32c,
+            Verification::Md5 => true,
+        };
+        let mut writer = writer.map(|writer| OrderedWriter {
+            writer,
+            md5: needs_md5.then(md5::Context::new),
+        });
+
+        let completed = resume
+            .as_ref()
+            .map(|s| s.completed.clone())
+            .unwrap_or_default();
+        let resumed_bytes = resume.as_ref().map_or(0, |s| {
+            stripes(size, s.probe_size, s.stripe_size)
+                .into_iter()
+                .filter(|(offset, _)| completed.contains(offset))
+                .map(|(_, len)| len)
+                .sum()
+        });
+        let progress = ProgressTracker {
+            sender: &self.progress,
+            start,
+            resumed_bytes,
+        };
+        progress.update(|p| {
+            p.bytes = resumed_bytes;
+            p.total_bytes = size;
+        });
+
+        // 最初のストライプをダウンロードして、帯域幅を測定
+        let probe = StripeContext {
+            sink: &sink,
+            journal: None,
+            generation: metadata.generation,
+            progress: &progress,
+        };
+        let mut results = BTreeMap::new();
+        let mut tuning = None;
+        if size > 0 && !completed.contains(&0) {
+            let transfer_start = Instant::now();
+            let limit = probe_size.min(size);
+            let mut result = self.write_stripe(&probe, Some(reader), 0, limit).await?;
+            result.retries += probe_retries;
+            if let Some(writer) = &mut writer {
+                writer.write(std::mem::take(&mut result.data)).await?;
+            }
+            results.insert(0, result);
+            if self.auto_tuning {
+                let bandwidth = limit as f64 / transfer_start.elapsed().as_secs_f64();
+                tuning = Some(tune(bandwidth, latency));
+            }
+        } else {
+            drop(reader);
+        }
+
+        // 再開したダウンロードでは、ジャーナルに記録されたストライプサイズを使用
+        let stripe_size = match (&resume, &mut tuning) {
+            (Some(state), Some(tuning)) => {
+                tuning.stripe_size = state.stripe_size;
+                state.stripe_size
+            }
+            (Some(state), None) => state.stripe_size,
+            (None, Some(tuning)) => tuning.stripe_size,
+            (None, None) => self.stripe_size,
+        };
+        let max_in_flight = tuning
+            .as_ref()
+            .map_or(self.max_in_flight, |t| t.max_in_flight);
+
+        let journal = match (self.journal, destination, &resume) {
+            (true, Some(destination), Some(_)) => Some(Journal::append(destination).await?),
+            (true, Some(destination), None) => Some(
+                Journal::create(destination, metadata.generation, probe_size, stripe_size).await?,
+            ),
+            (_, _, _) => None,
+        };
+        if results.contains_key(&0) {
+            record(journal.as_ref(), 0).await?;
+        }
+
+        // 残りのストライプは、最初のストライプと同じ世代を指定してダウンロード
+        // 同時にダウンロードするストライプの数は`max_in_flight`までに制限
+        let stripes = stripes(size, probe_size, stripe_size);
+        progress.update(|p| {
+            p.stripes = completed.len() + results.len();
+            p.total_stripes = stripes.len();
+        });
+        let context = StripeContext {
+            journal: journal.as_ref(),
+            ..probe
+        };
+        let downloads = futures::stream::iter(stripes.iter().skip(1))
+            .filter(|(offset, _)| std::future::ready(!completed.contains(offset)))
+            .map(|&(offset, limit)| self.write_stripe(&context, None, offset, limit));
+        match &mut writer {
+            // `buffered`はオフセットの順に結果を返すため、先に完了したストライプは前のストライプが完了するまで待機
+            Some(writer) => {
+                let mut downloads = std::pin::pin!(downloads.buffered(max_in_flight));
+                while let Some(mut result) = downloads.try_next().await? {
+                    writer.write(std::mem::take(&mut result.data)).await?;
+                    results.insert(result.offset, result);
+                }
+                writer.writer.flush().await?;
+            }
+            None => results.extend(
+                downloads
+                    .buffer_unordered(max_in_flight)
+                    .map_ok(|r| (r.offset, r))
+                    .try_collect::<Vec<_>>()
+                    .await?,
+            ),
+        }
+        let mut crcs = results
+            .values()
+            .map(|r| (r.offset, r.crc))
+            .collect::<BTreeMap<_, _>>();
+
+        if let Some(journal) = journal {
+            journal.remove().await?;
+        }
+
+        // 前回のダウンロードで完了していたストライプのCRC32Cは、ファイルから計算
+        if let Some(destination) = destination
+            && self.verification == Verification::Crc32c
+            && has_crc32c
+        {
+            let missing = stripes
+                .iter()
+                .filter(|(o, _)| !crcs.contains_key(o))
+                .collect::<Vec<_>>();
+            for (offset, len) in missing {
+                let mut crc = 0;
+                read_file(destination, *offset, *len, |b| {
+                    crc = crc32c::crc32c_append(crc, b)
+                })
+                .await?;
+                crcs.insert(*offset, crc);
+            }
+        }
+        // MD5は、検証に必要な場合のみ計算
+        let md5 = async {
+            match (&sink, writer) {
+                (StripeSink::File(destination), _) => {
+                    let mut context = md5::Context::new();
+                    read_file(destination, 0, size, |b| context.consume(b)).await?;
+                    Ok(context.finalize().0.to_vec())
+                }
+                (StripeSink::Buffer(buffer), _) => {
+                    let digest = md5::compute(&buffer.lock().unwrap()[..]);
+                    Ok(digest.0.to_vec())
+                }
+                (StripeSink::Memory, writer) => {
+                    let context = writer.and_then(|w| w.md5);
+                    Ok(context
+                        .ok_or(DownloadError::ChecksumUnavailable)?
+                        .finalize()
+                        .0
+                        .to_vec())
+                }
+            }
+        };
+        verify(self.verification, &metadata, &stripes, &crcs, md5).await?;
+
+        let report = DownloadReport {
+            bytes: size,
+            elapsed: start.elapsed(),
+            stripes: stripes.len(),
+            resumed: stripes
+                .iter()
+                .filter(|(offset, _)| completed.contains(offset))
+                .count(),
+            retries: stripes
+                .iter()
+                .map(|(offset, _)| results.get(offset).map_or(0, |r| r.retries))
+                .collect(),
+            tuning,
+        };
+        Ok((report, buffer.map(|b| b.into_inner().unwrap())))
+    }
+
+    async fn read_stripe(
+        &self,
+        offset: u64,
+        limit: u64,
+        generation: Option<i64>,
+    ) -> gcs::Result<ReadObjectResponse> {
+        let mut builder = self
+            .client
+            .read_object(&self.bucket, &self.object)
+            .set_read_range(ReadRange::segment(offset, limit));
+        if let Some(generation) = generation {
+            builder = builder.set_generation(generation);
+        }
+        builder.send().await
+    }
+
+    // ストライプをダウンロードして、ダウンロード先に書き込み
+    //
+    // `reader`が指定された場合は、最初の試行でそれを使用する。
+    // 失敗した場合は、ストライプのオフセットに戻って、そのストライプの範囲のみを書き直す。
+    // オブジェクトが変更された場合は再試行せずに`ObjectChanged`を返し、他のストライプのダウンロードも中止される。
+    async fn write_stripe(
+        &self,
+        context: &StripeContext<'_>,
+        reader: Option<ReadObjectResponse>,
+        offset: u64,
+        limit: u64,
+    ) -> anyhow::Result<StripeResult> {
+        let mut reader = reader;
+        let ((crc, data), retries) = self
+            .retry(|| {
+                let reader = reader.take();
+                async move {
+                    let reader = match reader {
+                        Some(reader) => reader,
+                        None => self
+                            .read_stripe(offset, limit, Some(context.generation))
+                            .await
+                            .map_err(|e| pinned_error(e, context.generation))?,
+                    };
+                    copy_stripe(reader, context.sink, offset, limit, context.progress).await
+                }
+            })
+            .await?;
+        record(context.journal, offset).await?;
+        context.progress.update(|p| p.stripes += 1);
+        Ok(StripeResult {
+            offset,
+            crc,
+            retries,
+            data,
+        })
+    }
+
+    // 再試行ポリシーに従って、`attempt`を再試行
+    //
+    // ストレージのエラーのみを再試行して、結果と再試行した回数を返す。
+    async fn retry<F, Fut, R>(&self, mut attempt: F) -> anyhow::Result<(R, u32)>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<R>>,
+    {
+        // ストライプの読み込みは冪等
+        let mut state = RetryState::new(true);
+        loop {
+            let error = match attempt().await {
+                Ok(r) => return Ok((r, state.attempt_count)),
+                Err(e) => e.downcast::<gcs::Error>()?,
+            };
+            state.attempt_count += 1;
+            match self.retry_policy.on_error(&state, error) {
+                RetryResult::Continue(_) => {
+                    let delay = self.backoff_policy.on_failure(&state);
+                    tokio::time::sleep(delay).await;
+                }
+                RetryResult::Exhausted(e) | RetryResult::Permanent(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+// ダウンロード先
+enum Target<'a> {
+    File(&'a Path),
+    Buffer,
+    Writer(&'a mut (dyn AsyncWrite + Unpin + Send)),
+}
+
+// ストライプのダウンロードで共有する状態
+struct StripeContext<'a> {
+    sink: &'a StripeSink<'a>,
+    journal: Option<&'a Journal>,
+    generation: i64,
+    progress: &'a ProgressTracker<'a>,
+}
+
+// ダウンロードの進捗を更新して、スループットと残り時間を計算
+struct ProgressTracker<'a> {
+    sender: &'a watch::Sender<DownloadProgress>,
+    start: Instant,
+    // 前回のダウンロードで完了していたバイト数
+    resumed_bytes: u64,
+}
+
+impl ProgressTracker<'_> {
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut DownloadProgress),
+    {
+        self.sender.send_modify(|p| {
+            f(p);
+            let elapsed = self.start.elapsed().as_secs_f64();
+            if elapsed > 0.0 {
+                p.throughput = p.bytes.saturating_sub(self.resumed_bytes) as f64 / elapsed;
+            }
+            p.eta = (p.throughput > 0.0).then(|| {
+                Duration::from_secs_f64(p.total_bytes.saturating_sub(p.bytes) as f64 / p.throughput)
+            });
+        });
+    }
+}
+
+// ダウンロードしたストライプ
+struct StripeResult {
+    offset: u64,
+    crc: u32,
+    retries: u32,
+    // 書き込み先に順に書き込むために、メモリに保持したストライプのデータ
+    data: Bytes,
+}
+
+// 世代を固定した読み込みのエラーを変換
+//
+// 412（FailedPrecondition）と404（NotFound）は、オブジェクトが上書きまたは削除されたことを示すため、
+// 再試行しても成功しない`ObjectChanged`に変換する。
+fn pinned_error(error: gcs::Error, generation: i64) -> anyhow::Error {
+    let changed = matches!(error.http_status_code(), Some(404 | 412))
+        || error
+            .status()
+            .is_some_and(|s| matches!(s.code, Code::NotFound | Code::FailedPrecondition));
+    if changed {
+        return DownloadError::ObjectChanged {
+            expected_generation: generation,
+        }
+        .into();
+    }
+    error.into()
+}
+
+// ストライプのダウンロードが完了したことをジャーナルに記録
+async fn record(journal: Option<&Journal>, offset: u64) -> anyhow::Result<()> {
+    if let Some(journal) = journal {
+        journal.record(offset).await?;
+    }
+    Ok(())
+}
+
+// ストライプの書き込み先
+enum StripeSink<'a> {
+    // ファイルのストライプの位置に書き込み
+    //
+    // 複製したファイルハンドルは位置を共有するため、ストライプごとにファイルを開く
+    File(&'a Path),
+    // バッファーのストライプの位置に書き込み
+    Buffer(&'a std::sync::Mutex<BytesMut>),
+    // ストライプごとにメモリに保持して、呼び出し元がオフセットの順に書き込み
+    Memory,
+}
+
+impl StripeSink<'_> {
+    // ストライプの`offset`の位置から書き込むライターを作成
+    async fn writer(&self, offset: u64, limit: u64) -> std::io::Result<StripeWriter<'_>> {
+        match self {
+            Self::File(path) => {
+                let mut writer = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+                writer.seek(std::io::SeekFrom::Start(offset)).await?;
+                Ok(StripeWriter::File(writer))
+            }
+            Self::Buffer(buffer) => Ok(StripeWriter::Buffer {
+                buffer,
+                position: offset as usize,
+            }),
+            Self::Memory => Ok(StripeWriter::Memory(BytesMut::with_capacity(
+                limit as usize,
+            ))),
+        }
+    }
+}
+
+// 1つのストライプを書き込むライター
+enum StripeWriter<'a> {
+    File(tokio::fs::File),
+    Buffer {
+        buffer: &'a std::sync::Mutex<BytesMut>,
+        position: usize,
+    },
+    Memory(BytesMut),
+}
+
+impl StripeWriter<'_> {
+    async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Self::File(writer) => writer.write_all(data).await,
+            Self::Buffer { buffer, position } => {
+                let mut buffer = buffer.lock().unwrap();
+                let target = buffer
+                    .get_mut(*position..*position + data.len())
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "the stripe exceeds the object size",
+                        )
+                    })?;
+                target.copy_from_slice(data);
+                *position += data.len();
+                Ok(())
+            }
+            Self::Memory(buffer) => {
+                buffer.extend_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+
+    // 書き込みを完了して、メモリに保持したデータを返す
+    async fn finish(self) -> std::io::Result<Bytes> {
+        match self {
+            Self::File(mut writer) => {
+                writer.flush().await?;
+                Ok(Bytes::new())
+            }
+            Self::Buffer { .. } => Ok(Bytes::new()),
+            Self::Memory(buffer) => Ok(buffer.freeze()),
+        }
+    }
+}
+
+// ストライプをオフセットの順に書き込むライター
+struct OrderedWriter<'a> {
+    writer: &'a mut (dyn AsyncWrite + Unpin + Send),
+    md5: Option<md5::Context>,
+}
+
+impl OrderedWriter<'_> {
+    async fn write(&mut self, data: Bytes) -> std::io::Result<()> {
+        if let Some(md5) = &mut self.md5 {
+            md5.consume(&data);
+        }
+        self.writer.write_all(&data).await
+    }
+}
+
+// ストライプのデータを、ダウンロード先の`offset`の位置から書き込み
+//
+// 書き込んだデータのCRC32Cと、メモリに保持した場合はそのデータを返す。
+// 失敗した場合は、書き込んだバイト数を進捗から取り除く。
+// レスポンスが`limit`バイトより前に終わった場合は、再試行できるI/Oエラーを返す。
+async fn copy_stripe(
+    mut reader: ReadObjectResponse,
+    sink: &StripeSink<'_>,
+    offset: u64,
+    limit: u64,
+    progress: &ProgressTracker<'_>,
+) -> anyhow::Result<(u32, Bytes)> {
+    let mut written = 0;
+    let result = async {
+        let mut writer = sink.writer(offset, limit).await?;
+        let mut crc = 0;
+        while let Some(b) = reader.next().await.transpose()? {
+            crc = crc32c::crc32c_append(crc, &b);
+            writer.write(&b).await?;
+            written += b.len() as u64;
+            progress.update(|p| p.bytes += b.len() as u64);
+        }
+        if written != limit {
+            let message = format!("the stripe at {offset} ended after {written} of {limit} bytes");
+            return Err(gcs::Error::io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                message,
+            ))
+            .into());
+        }
+        Ok::<_, anyhow::Error>((crc, writer.finish().await?))
+    }
+    .await;
+    if result.is_err() {
+        progress.update(|p| p.bytes -= written);
+    }
+    result
+}
+
+// ファイルの`offset`の位置から`len`バイトを読み込み、チャンクごとに`f`を呼び出す
+async fn read_file<F>(path: &Path, offset: u64, len: u64, mut f: F) -> anyhow::Result<()>
+where
+    F: FnMut(&[u8]),
+{
+    let mut reader = tokio::fs::File::open(path).await?;
+    reader.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut reader = reader.take(len);
+    let mut buffer = vec![0_u8; 1024 * 1024];
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        f(&buffer[..n]);
+    }
+    Ok(())
+}
+
+// ダウンロードしたデータをオブジェクトのチェックサムと比較
+//
+// `crcs`はストライプのオフセットと、そのストライプのCRC32Cの組で、オフセットの順に結合する。
+// `md5`はダウンロードしたデータのMD5を計算するフューチャーで、MD5で検証する場合のみ待機する。
+async fn verify<F>(
+    verification: Verification,
+    metadata: &ObjectHighlights,
+    stripes: &[(u64, u64)],
+    crcs: &BTreeMap<u64, u32>,
+    md5: F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = anyhow::Result<Vec<u8>>>,
+{
+    let checksums = metadata.checksums.clone().unwrap_or_default();
+    let expected_md5 = (!checksums.md5_hash.is_empty()).then_some(checksums.md5_hash);
+    match (verification, checksums.crc32c, expected_md5) {
+        (Verification::None, _, _) => Ok(()),
+        (Verification::Crc32c, Some(expected), _) => {
+            let actual = stripes.iter().fold(0, |acc, (offset, len)| {
+                crc32c::crc32c_combine(acc, crcs[offset], *len as usize)
+            });
+            if actual != expected {
+                return Err(DownloadError::Crc32cMismatch { expected, actual }.into());
+            }
+            Ok(())
+        }
+        // CRC32Cがない場合は、MD5を比較
+        (Verification::Crc32c, None, Some(expected)) | (Verification::Md5, _, Some(expected)) => {
+            let actual = md5.await?;
+            if actual != expected {
+                return Err(DownloadError::Md5Mismatch {
+                    expected: expected.to_vec(),
+                    actual,
+                }
+                .into());
+            }
+            Ok(())
+        }
+        (_, _, _) => Err(DownloadError::ChecksumUnavailable.into()),
+    }
+}
+
+// オブジェクトを`(オフセット, 長さ)`のストライプに分割
+//
+// 最初のストライプのみ`first`バイトで、残りは`stripe_size`バイトずつ分割する。
+pub(crate) fn stripes(size: u64, first: u64, stripe_size: u64) -> Vec<(u64, u64)> {
+    if size == 0 {
+        return vec![];
+    }
+    let first = first.min(size);
+    let rest = (0..(size - first).div_ceil(stripe_size)).map(|i| {
+        let offset = first + i * stripe_size;
+        (offset, stripe_size.min(size - offset))
+    });
+    std::iter::once((0, first)).chain(rest).collect()
+}
+
+// 最初のストライプで測定した帯域幅（バイト/秒）とレイテンシから、ストライプサイズと同時にダウンロードする
+// ストライプの最大数を選択
+//
+// モジュールの説明にある表に従って、高速で低遅延なネットワークほど大きなストライプを選択する。
+fn tune(bandwidth: f64, latency: Duration) -> Tuning {
+    const MIB: f64 = 1024.0 * 1024.0;
+    let (stripe_size, max_in_flight) =
+        if bandwidth >= 64.0 * MIB && latency <= Duration::from_millis(20) {
+            (32 * 1024 * 1024, 32)
+        } else if bandwidth >= 16.0 * MIB {
+            (16 * 1024 * 1024, DEFAULT_MAX_IN_FLIGHT)
+        } else {
+            (DEFAULT_STRIPE_SIZE, 8)
+        };
+    Tuning {
+        stripe_size,
+        max_in_flight,
+        bandwidth,
+        latency,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::{DownloadError, ParallelDownloader, Verification, stripes, tune};
+    use crate::{FakeStorage, Fault, FaultInjector, Operation, Rule, Trigger};
+    use gcs::Result;
+    use gcs::model::{Object, ObjectChecksums, ReadObjectRequest};
+    use gcs::model_ext::{ObjectHighlights, WriteObjectRequest};
+    use gcs::read_object::ReadObjectResponse;
+    use gcs::request_options::RequestOptions;
+    use gcs::streaming_source::{Seek, StreamingSource};
+    use google_cloud_gax::error::rpc::{Code, Status};
+    use google_cloud_gax::exponential_backoff::ExponentialBackoffBuilder;
+    use google_cloud_gax::retry_policy::{AlwaysRetry, RetryPolicyExt as _};
+    use google_cloud_storage as gcs;
+
+    mockall::mock! {
+        #[derive(Debug)]
+        Storage {}
+
+        impl gcs::stub::Storage for Storage {
+            async fn read_object(&self, _req: ReadObjectRequest, _options: RequestOptions) -> Result<ReadObjectResponse>;
+
+            async fn write_object_buffered<P: StreamingSource + Send + Sync + 'static>(
+                &self,
+                _payload: P,
+                _req: WriteObjectRequest,
+                _options: RequestOptions,
+            ) -> Result<Object>;
+
+            async fn write_object_unbuffered<P: StreamingSource + Seek + Send + Sync + 'static>(
+                &self,
+                _payload: P,
+                _req: WriteObjectRequest,
+                _options: RequestOptions,
+            ) -> Result<Object>;
+        }
+    }
+
+    // 要求された範囲のデータを返す
+    fn ranged(
+        contents: bytes::Bytes,
+        generation: i64,
+    ) -> impl FnMut(ReadObjectRequest, RequestOptions) -> Result<ReadObjectResponse> + Send + 'static
+    {
+        let mut highlights = ObjectHighlights::default();
+        highlights.generation = generation;
+        highlights.checksums = Some(
+            ObjectChecksums::new()
+                .set_crc32c(crc32c::crc32c(&contents))
+                .set_md5_hash(bytes::Bytes::from_owner(md5::compute(&contents).0)),
+        );
+        serve(contents, highlights)
+    }
+
+    // 要求された範囲のデータを、指定されたメタデータとともに返す
+    fn serve(
+        contents: bytes::Bytes,
+        mut highlights: ObjectHighlights,
+    ) -> impl FnMut(ReadObjectRequest, RequestOptions) -> Result<ReadObjectResponse> + Send + 'static
+    {
+        highlights.size = contents.len() as i64;
+        move |r, _| {
+            let start = r.read_offset as usize;
+            let end = match r.read_limit {
+                0 => contents.len(),
+                n => (start + n as usize).min(contents.len()),
+            };
+            // 最初のストライプ以外は、世代が固定されていなければならない
+            assert!(start == 0 || r.generation == highlights.generation, "{r:?}");
+            Ok(ReadObjectResponse::from_source(
+                highlights.clone(),
+                contents.slice(start..end),
+            ))
+        }
+    }
+
+    fn ranged_stub(contents: bytes::Bytes, generation: i64) -> MockStorage {
+        let mut mock = MockStorage::new();
+        mock.expect_read_object()
+            .returning(ranged(contents, generation));
+        mock
+    }
+
+    #[test]
+    fn test_stripes() {
+        assert_eq!(stripes(0, 4, 4), vec![]);
+        assert_eq!(stripes(8, 4, 4), vec![(0, 4), (4, 4)]);
+        assert_eq!(stripes(10, 4, 4), vec![(0, 4), (4, 4), (8, 2)]);
+        assert_eq!(stripes(2, 4, 4), vec![(0, 2)]);
+        assert_eq!(stripes(22, 4, 8), vec![(0, 4), (4, 8), (12, 8), (20, 2)]);
+    }
+
+    #[test]
+    fn test_tune() {
+        const MIB: f64 = 1024.0 * 1024.0;
+        // GCP内のVM間のような高速で低遅延なネットワーク
+        let tuning = tune(200.0 * MIB, Duration::from_millis(5));
+        assert_eq!(
+            (tuning.stripe_size, tuning.max_in_flight),
+            (32 * 1024 * 1024, 32)
+        );
+        // 高速だが遅延が大きいネットワーク
+        let tuning = tune(200.0 * MIB, Duration::from_millis(80));
+        assert_eq!(
+            (tuning.stripe_size, tuning.max_in_flight),
+            (16 * 1024 * 1024, 16)
+        );
+        // 家庭のネットワーク
+        let tuning = tune(5.0 * MIB, Duration::from_millis(30));
+        assert_eq!(
+            (tuning.stripe_size, tuning.max_in_flight),
+            (8 * 1024 * 1024, 8)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from_iter((0..1000).map(|i| (i % 251) as u8));
+        let client = gcs::client::Storage::from_stub(ranged_stub(contents.clone(), 42));
+        let destination = std::env::temp_dir().join(format!("download-{}", uuid::Uuid::new_v4()));
+
+        let downloader =
+            ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+                .with_stripe_size(64);
+        let progress = downloader.subscribe();
+        let report = downloader.download(&destination).await?;
+        assert_eq!(report.bytes, 1000);
+        assert_eq!(report.stripes, 16);
+        assert_eq!(tokio::fs::read(&destination).await?, contents);
+
+        let progress = progress.borrow().clone();
+        assert_eq!((progress.bytes, progress.total_bytes), (1000, 1000));
+        assert_eq!((progress.stripes, progress.total_stripes), (16, 16));
+        assert_eq!(progress.eta, Some(std::time::Duration::ZERO));
+
+        tokio::fs::remove_file(&destination).await?;
+        Ok(())
+    }
+
+    // 同時に読み込み中のストライプ数を記録するソース
+    #[derive(Debug)]
+    struct TrackedSource {
+        contents: Option<bytes::Bytes>,
+        active: Arc<AtomicUsize>,
+    }
+
+    impl StreamingSource for TrackedSource {
+        type Error = std::convert::Infallible;
+
+        async fn next(&mut self) -> Option<std::result::Result<bytes::Bytes, Self::Error>> {
+            tokio::task::yield_now().await;
+            self.contents.take().map(Ok)
+        }
+    }
+
+    impl Drop for TrackedSource {
+        fn drop(&mut self) {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_auto_tuning() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from_iter((0..1000).map(|i| (i % 251) as u8));
+        let client = gcs::client::Storage::from_stub(ranged_stub(contents.clone(), 42));
+        let destination = std::env::temp_dir().join(format!("download-{}", uuid::Uuid::new_v4()));
+
+        let report = ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+            .with_stripe_size(64)
+            .with_auto_tuning(true)
+            .download(&destination)
+            .await?;
+        // 選択されるストライプサイズは8MiB以上のため、残りのデータは1つのストライプになる
+        let tuning = report.tuning.expect("auto tuning is enabled");
+        assert!(tuning.stripe_size >= 8 * 1024 * 1024, "{tuning:?}");
+        assert_eq!(report.stripes, 2);
+        assert_eq!(tokio::fs::read(&destination).await?, contents);
+
+        tokio::fs::remove_file(&destination).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_max_in_flight() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from(vec![b'x'; 1000]);
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let mut mock = MockStorage::new();
+        mock.expect_read_object().returning({
+            let active = active.clone();
+            let max_active = max_active.clone();
+            move |r, _| {
+                let n = active.fetch_add(1, Ordering::SeqCst) + 1;
+                max_active.fetch_max(n, Ordering::SeqCst);
+                let start = r.read_offset as usize;
+                let end = (start + r.read_limit as usize).min(contents.len());
+                let mut highlights = ObjectHighlights::default();
+                highlights.size = contents.len() as i64;
+                Ok(ReadObjectResponse::from_source(
+                    highlights,
+                    TrackedSource {
+                        contents: Some(contents.slice(start..end)),
+                        active: active.clone(),
+                    },
+                ))
+            }
+        });
+        let client = gcs::client::Storage::from_stub(mock);
+        let destination = std::env::temp_dir().join(format!("download-{}", uuid::Uuid::new_v4()));
+
+        let report = ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+            .with_stripe_size(10)
+            .with_max_in_flight(3)
+            .download(&destination)
+            .await?;
+        assert_eq!(report.stripes, 100);
+        assert!(max_active.load(Ordering::SeqCst) <= 3);
+
+        tokio::fs::remove_file(&destination).await?;
+        Ok(())
+    }
+
+    // 先頭の`done`個のストライプのダウンロードが完了した状態を作成
+    async fn partial_download(
+        destination: &std::path::Path,
+        contents: &[u8],
+        generation: i64,
+        stripe_size: usize,
+        done: usize,
+    ) -> anyhow::Result<()> {
+        let mut partial = vec![0_u8; contents.len()];
+        partial[..stripe_size * done].copy_from_slice(&contents[..stripe_size * done]);
+        tokio::fs::write(destination, partial).await?;
+        let mut journal = format!(
+            "generation={generation}\nprobe_size={stripe_size}\nstripe_size={stripe_size}\n"
+        );
+        for i in 0..done {
+            journal.push_str(&format!("done={}\n", i * stripe_size));
+        }
+        tokio::fs::write(super::Journal::path(destination), journal).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_resume() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from_iter((0..1000).map(|i| (i % 251) as u8));
+        let destination = std::env::temp_dir().join(format!("download-{}", uuid::Uuid::new_v4()));
+        partial_download(&destination, &contents, 42, 64, 13).await?;
+
+        // 16個のストライプのうち、最初のストライプと未完了の3個のストライプのみを読み込む
+        let mut mock = MockStorage::new();
+        mock.expect_read_object()
+            .times(4)
+            .returning(ranged(contents.clone(), 42));
+        let client = gcs::client::Storage::from_stub(mock);
+
+        let report = ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+            .with_journal(true)
+            .download(&destination)
+            .await?;
+        assert_eq!(report.stripes, 16);
+        assert_eq!(report.resumed, 13);
+        assert_eq!(tokio::fs::read(&destination).await?, contents);
+        assert!(!tokio::fs::try_exists(super::Journal::path(&destination)).await?);
+
+        tokio::fs::remove_file(&destination).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_resume_generation_changed() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from_iter((0..1000).map(|i| (i % 251) as u8));
+        let destination = std::env::temp_dir().join(format!("download-{}", uuid::Uuid::new_v4()));
+        partial_download(&destination, &contents, 41, 64, 3).await?;
+
+        // 世代が異なるため、古いジャーナルを破棄して16個のストライプをすべて読み込む
+        let mut mock = MockStorage::new();
+        mock.expect_read_object()
+            .times(16)
+            .returning(ranged(contents.clone(), 42));
+        let client = gcs::client::Storage::from_stub(mock);
+
+        let report = ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+            .with_stripe_size(64)
+            .with_journal(true)
+            .download(&destination)
+            .await?;
+        assert_eq!(report.stripes, 16);
+        assert_eq!(report.resumed, 0);
+        assert_eq!(tokio::fs::read(&destination).await?, contents);
+        assert!(!tokio::fs::try_exists(super::Journal::path(&destination)).await?);
+
+        tokio::fs::remove_file(&destination).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_verification() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from_iter((0..1000).map(|i| (i % 251) as u8));
+        let destination = std::env::temp_dir().join(format!("download-{}", uuid::Uuid::new_v4()));
+
+        for verification in [Verification::Crc32c, Verification::Md5] {
+            let client = gcs::client::Storage::from_stub(ranged_stub(contents.clone(), 42));
+            ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+                .with_stripe_size(64)
+                .with_verification(verification)
+                .download(&destination)
+                .await?;
+        }
+
+        // 再開したダウンロードは、完了していたストライプのCRC32Cをファイルから計算
+        partial_download(&destination, &contents, 42, 64, 5).await?;
+        let client = gcs::client::Storage::from_stub(ranged_stub(contents.clone(), 42));
+        ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+            .with_journal(true)
+            .with_verification(Verification::Crc32c)
+            .download(&destination)
+            .await?;
+
+        tokio::fs::remove_file(&destination).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_checksum_mismatch() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from(vec![b'x'; 1000]);
+        let destination = std::env::temp_dir().join(format!("download-{}", uuid::Uuid::new_v4()));
+        let mut highlights = ObjectHighlights::default();
+        highlights.checksums = Some(
+            ObjectChecksums::new()
+                .set_crc32c(0_u32)
+                .set_md5_hash(bytes::Bytes::from_static(&[0; 16])),
+        );
+
+        let mut mock = MockStorage::new();
+        mock.expect_read_object()
+            .returning(serve(contents.clone(), highlights));
+        let client = gcs::client::Storage::from_stub(mock);
+
+        let err = ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+            .with_stripe_size(64)
+            .with_verification(Verification::Crc32c)
+            .download(&destination)
+            .await
+            .expect_err("the CRC32C does not match");
+        assert!(
+            matches!(
+                err.downcast_ref::<DownloadError>(),
+                Some(DownloadError::Crc32cMismatch { expected: 0, .. })
+            ),
+            "{err:?}"
+        );
+
+        let err = ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+            .with_stripe_size(64)
+            .with_verification(Verification::Md5)
+            .download(&destination)
+            .await
+            .expect_err("the MD5 does not match");
+        assert!(
+            matches!(
+                err.downcast_ref::<DownloadError>(),
+                Some(DownloadError::Md5Mismatch { .. })
+            ),
+            "{err:?}"
+        );
+
+        tokio::fs::remove_file(&destination).await?;
+        Ok(())
+    }
+
+    // データの前半を返した後に失敗するソース
+    #[derive(Debug)]
+    struct InterruptedSource(Option<bytes::Bytes>);
+
+    impl StreamingSource for InterruptedSource {
+        type Error = std::io::Error;
+
+        async fn next(&mut self) -> Option<std::result::Result<bytes::Bytes, Self::Error>> {
+            match self.0.take() {
+                Some(b) => Some(Ok(b.slice(..b.len() / 2))),
+                None => Some(Err(std::io::Error::from(
+                    std::io::ErrorKind::ConnectionReset,
+                ))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_retry() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from_iter((0..1000).map(|i| (i % 251) as u8));
+        let destination = std::env::temp_dir().join(format!("download-{}", uuid::Uuid::new_v4()));
+
+        // オフセット128のストライプはリクエストが失敗し、オフセット256のストライプは読み込み中に失敗
+        let failed = Arc::new(Mutex::new(HashSet::new()));
+        let mut respond = ranged(contents.clone(), 42);
+        let mut mock = MockStorage::new();
+        let body = contents.clone();
+        mock.expect_read_object().returning(move |r, o| {
+            let offset = r.read_offset;
+            if !failed.lock().unwrap().insert(offset) {
+                return respond(r, o);
+            }
+            match offset {
+                128 => Err(gcs::Error::service(
+                    Status::default()
+                        .set_code(Code::Unavailable)
+                        .set_message("try again"),
+                )),
+                256 => {
+                    let mut highlights = ObjectHighlights::default();
+                    highlights.size = body.len() as i64;
+                    highlights.generation = 42;
+                    Ok(ReadObjectResponse::from_source(
+                        highlights,
+                        InterruptedSource(Some(body.slice(256..320))),
+                    ))
+                }
+                _ => respond(r, o),
+            }
+        });
+        let client = gcs::client::Storage::from_stub(mock);
+
+        let downloader =
+            ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+                .with_stripe_size(64)
+                .with_retry_policy(AlwaysRetry.with_attempt_limit(3))
+                .with_backoff_policy(
+                    ExponentialBackoffBuilder::new()
+                        .with_initial_delay(Duration::from_millis(1))
+                        .with_maximum_delay(Duration::from_millis(1))
+                        .build()?,
+                )
+                .with_verification(Verification::Crc32c);
+        let progress = downloader.subscribe();
+        let report = downloader.download(&destination).await?;
+        // 失敗した試行で書き込んだバイト数は、進捗に含まれない
+        assert_eq!(progress.borrow().bytes, 1000);
+        let mut expected = vec![0; 16];
+        expected[2] = 1;
+        expected[4] = 1;
+        assert_eq!(report.retries, expected);
+        assert_eq!(tokio::fs::read(&destination).await?, contents);
+
+        tokio::fs::remove_file(&destination).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_short_stripe() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from_iter((0..1000).map(|i| (i % 251) as u8));
+        let destination = std::env::temp_dir().join(format!("download-{}", uuid::Uuid::new_v4()));
+
+        // オフセット256のストライプは、最初のレスポンスがエラーなしで途中で終わる
+        let truncated = Arc::new(Mutex::new(false));
+        let mut respond = ranged(contents.clone(), 42);
+        let mut mock = MockStorage::new();
+        let body = contents.clone();
+        mock.expect_read_object().returning(move |r, o| {
+            if r.read_offset != 256 || std::mem::replace(&mut *truncated.lock().unwrap(), true) {
+                return respond(r, o);
+            }
+            let mut highlights = ObjectHighlights::default();
+            highlights.size = body.len() as i64;
+            highlights.generation = 42;
+            Ok(ReadObjectResponse::from_source(
+                highlights,
+                body.slice(256..300),
+            ))
+        });
+        let client = gcs::client::Storage::from_stub(mock);
+
+        // 検証しない場合も、途中で終わったストライプは再試行する
+        let downloader =
+            ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+                .with_stripe_size(64)
+                .with_backoff_policy(
+                    ExponentialBackoffBuilder::new()
+                        .with_initial_delay(Duration::from_millis(1))
+                        .with_maximum_delay(Duration::from_millis(1))
+                        .build()?,
+                )
+                .with_verification(Verification::None);
+        let progress = downloader.subscribe();
+        let report = downloader.download(&destination).await?;
+        assert_eq!(progress.borrow().bytes, 1000);
+        let mut expected = vec![0; 16];
+        expected[4] = 1;
+        assert_eq!(report.retries, expected);
+        assert_eq!(tokio::fs::read(&destination).await?, contents);
+
+        tokio::fs::remove_file(&destination).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_to_bytes() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from_iter((0..1000).map(|i| (i % 251) as u8));
+
+        for verification in [Verification::Crc32c, Verification::Md5] {
+            let client = gcs::client::Storage::from_stub(ranged_stub(contents.clone(), 42));
+            let (buffer, report) =
+                ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+                    .with_stripe_size(64)
+                    .with_verification(verification)
+                    .download_to_bytes()
+                    .await?;
+            assert_eq!(report.stripes, 16);
+            assert_eq!(Vec::from(buffer), contents);
+        }
+        Ok(())
+    }
+
+    // データを返す前に待機するソース
+    #[derive(Debug)]
+    struct DelayedSource {
+        contents: Option<bytes::Bytes>,
+        delay: Duration,
+    }
+
+    impl StreamingSource for DelayedSource {
+        type Error = std::convert::Infallible;
+
+        async fn next(&mut self) -> Option<std::result::Result<bytes::Bytes, Self::Error>> {
+            tokio::time::sleep(self.delay).await;
+            self.contents.take().map(Ok)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_to_writer() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from_iter((0..1000).map(|i| (i % 251) as u8));
+
+        // 2番目のストライプを遅延させて、後のストライプが先に完了するようにする
+        for verification in [Verification::Crc32c, Verification::Md5] {
+            let mut respond = ranged(contents.clone(), 42);
+            let delayed = contents.slice(64..128);
+            let mut mock = MockStorage::new();
+            mock.expect_read_object().returning(move |r, o| {
+                if r.read_offset != 64 {
+                    return respond(r, o);
+                }
+                let highlights = respond(r, o)?.object();
+                Ok(ReadObjectResponse::from_source(
+                    highlights,
+                    DelayedSource {
+                        contents: Some(delayed.clone()),
+                        delay: Duration::from_millis(50),
+                    },
+                ))
+            });
+            let client = gcs::client::Storage::from_stub(mock);
+
+            let mut writer = Vec::new();
+            let report =
+                ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+                    .with_stripe_size(64)
+                    .with_max_in_flight(4)
+                    .with_verification(verification)
+                    .download_to_writer(&mut writer)
+                    .await?;
+            assert_eq!(report.stripes, 16);
+            assert_eq!(writer, contents);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_object_changed() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from_iter((0..1000).map(|i| (i % 251) as u8));
+
+        for code in [Code::FailedPrecondition, Code::NotFound] {
+            // オフセット192以降のストライプを読み込むときに、オブジェクトが上書きされている
+            let requests = Arc::new(AtomicUsize::new(0));
+            let mut respond = ranged(contents.clone(), 42);
+            let mut mock = MockStorage::new();
+            mock.expect_read_object().returning({
+                let requests = requests.clone();
+                move |r, o| {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    if r.read_offset < 192 {
+                        return respond(r, o);
+                    }
+                    Err(gcs::Error::service(
+                        Status::default()
+                            .set_code(code)
+                            .set_message("generation mismatch"),
+                    ))
+                }
+            });
+            let client = gcs::client::Storage::from_stub(mock);
+
+            let err = ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+                .with_stripe_size(64)
+                .with_max_in_flight(2)
+                .with_retry_policy(AlwaysRetry.with_attempt_limit(3))
+                .download_to_bytes()
+                .await
+                .expect_err("the object was overwritten");
+            assert!(
+                matches!(
+                    err.downcast_ref::<DownloadError>(),
+                    Some(DownloadError::ObjectChanged {
+                        expected_generation: 42
+                    })
+                ),
+                "{err:?}"
+            );
+            // 再試行せずに、残りのストライプのダウンロードも中止
+            assert!(requests.load(Ordering::SeqCst) < 8, "{requests:?}");
+        }
+        Ok(())
+    }
+
+    // 最初の書き込みでオブジェクトを上書きするライター
+    struct OverwritingWriter {
+        fake: FakeStorage,
+        contents: Vec<u8>,
+    }
+
+    impl tokio::io::AsyncWrite for OverwritingWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            if self.contents.is_empty() {
+                self.fake
+                    .insert("projects/_/buckets/my-bucket", "my-object", "overwritten");
+            }
+            self.contents.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_fake() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from_iter((0..1000).map(|i| (i % 251) as u8));
+        let fake = FakeStorage::new();
+        let object = fake.insert(
+            "projects/_/buckets/my-bucket",
+            "my-object",
+            contents.clone(),
+        );
+        let client = gcs::client::Storage::from_stub(fake.clone());
+
+        let (buffer, report) =
+            ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+                .with_stripe_size(64)
+                .with_verification(Verification::Md5)
+                .download_to_bytes()
+                .await?;
+        assert_eq!(report.stripes, 16);
+        assert_eq!(Vec::from(buffer), contents);
+
+        // 最初のストライプを書き込んだ後にオブジェクトが上書きされると、残りのストライプは読み込めない
+        let mut writer = OverwritingWriter {
+            fake: fake.clone(),
+            contents: Vec::new(),
+        };
+        let err = ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+            .with_stripe_size(64)
+            .download_to_writer(&mut writer)
+            .await
+            .expect_err("the object was overwritten");
+        assert!(
+            matches!(
+                err.downcast_ref::<DownloadError>(),
+                Some(DownloadError::ObjectChanged { expected_generation }) if *expected_generation == object.generation
+            ),
+            "{err:?}"
+        );
+        assert_eq!(writer.contents, contents.slice(..64));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_faults() -> anyhow::Result<()> {
+        let contents = bytes::Bytes::from_iter((0..1000).map(|i| (i % 251) as u8));
+        let fake = FakeStorage::new();
+        fake.insert(
+            "projects/_/buckets/my-bucket",
+            "my-object",
+            contents.clone(),
+        );
+        let injector = FaultInjector::new(fake)
+            .with_seed(7)
+            .with_rule(Rule::new(
+                Operation::Read,
+                Trigger::Probability(0.2),
+                Fault::Error(Code::Unavailable),
+            ))
+            .with_rule(Rule::new(
+                Operation::Read,
+                Trigger::Nth(3),
+                Fault::Truncate(10),
+            ));
+        let client = gcs::client::Storage::from_stub(injector.clone());
+
+        let (buffer, report) =
+            ParallelDownloader::new(&client, "projects/_/buckets/my-bucket", "my-object")
+                .with_stripe_size(64)
+                .with_retry_policy(AlwaysRetry.with_attempt_limit(10))
+                .with_backoff_policy(
+                    ExponentialBackoffBuilder::new()
+                        .with_initial_delay(Duration::from_millis(1))
+                        .with_maximum_delay(Duration::from_millis(1))
+                        .build()?,
+                )
+                .with_verification(Verification::Crc32c)
+                .download_to_bytes()
+                .await?;
+        assert_eq!(Vec::from(buffer), contents);
+        // 注入した障害は、ストライプごとに再試行される
+        assert!(injector.injected() > 0);
+        assert!(report.retries.iter().sum::<u32>() > 0, "{report:?}");
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+//! # オフラインのテストで使用するメモリ上のGCS
+//!
+//! `FakeStorage`は`gcs::stub::Storage`を実装して、バケットとオブジェクトのマップをメモリ上に保持する。
+//! `mockall`のようにリクエストごとに期待値を記述する必要がないため、複数のリクエストを発行する関数をテストできる。
+//!
+//! オブジェクトのバージョニングは無効なバケットと同じように動作するため、オブジェクトを上書きすると古い世代は削除される。
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use bytes::{Bytes, BytesMut};
+use google_cloud_gax::error::rpc::{Code, Status};
+use google_cloud_storage as gcs;
+use google_cloud_storage::model::{Object, ObjectChecksums, ReadObjectRequest};
+use google_cloud_storage::model_ext::{ObjectHighlights, WriteObjectRequest};
+use google_cloud_storage::read_object::ReadObjectResponse;
+use google_cloud_storage::request_options::RequestOptions;
+use google_cloud_storage::streaming_source::{Seek, StreamingSource};
+
+/// メモリ上のバケットとオブジェクトに対して読み書きするスタブ
+///
+/// クローンしたスタブは状態を共有するため、`Storage::from_stub`に渡した後もオブジェクトを確認できる。
+/// バケット名は`projects/_/buckets/{bucket_name}`形式で指定する。
+#[derive(Clone, Debug, Default)]
+pub struct FakeStorage {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // (バケット名, オブジェクト名)をキーとした、最新の世代のオブジェクトとその内容
+    objects: BTreeMap<(String, String), (Object, Bytes)>,
+    // 最後に割り当てた世代
+    generation: i64,
+}
+
+impl FakeStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// オブジェクトを作成または上書きして、作成したオブジェクトを返す。
+    pub fn insert(&self, bucket: &str, object: &str, contents: impl Into<Bytes>) -> Object {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.generation + 1;
+        state.insert(
+            Object::new().set_bucket(bucket).set_name(object),
+            generation,
+            contents.into(),
+        )
+    }
+
+    /// 世代を指定して、オブジェクトを作成または上書きする。
+    ///
+    /// 以降に作成するオブジェクトには、指定した世代より大きい世代が割り当てられる。
+    pub fn insert_with_generation(
+        &self,
+        bucket: &str,
+        object: &str,
+        generation: i64,
+        contents: impl Into<Bytes>,
+    ) -> Object {
+        let mut state = self.state.lock().unwrap();
+        state.insert(
+            Object::new().set_bucket(bucket).set_name(object),
+            generation,
+            contents.into(),
+        )
+    }
+
+    /// オブジェクトのメタデータを返す。
+    pub fn object(&self, bucket: &str, object: &str) -> Option<Object> {
+        let state = self.state.lock().unwrap();
+        state.get(bucket, object).map(|(o, _)| o.clone())
+    }
+
+    /// オブジェクトの内容を返す。
+    pub fn contents(&self, bucket: &str, object: &str) -> Option<Bytes> {
+        let state = self.state.lock().unwrap();
+        state.get(bucket, object).map(|(_, c)| c.clone())
+    }
+
+    /// オブジェクトを削除して、削除した場合は`true`を返す。
+    pub fn remove(&self, bucket: &str, object: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state
+            .objects
+            .remove(&(bucket.to_string(), object.to_string()))
+            .is_some()
+    }
+
+    async fn write<P>(&self, payload: P, req: WriteObjectRequest) -> gcs::Result<Object>
+    where
+        P: StreamingSource + Send + Sync + 'static,
+    {
+        let resource = req.spec.resource.unwrap_or_default();
+        // ペイロードを読み込む前に前提条件を確認して、読み込んだ後に再度確認
+        let check = |state: &State| {
+            let current = state.get(&resource.bucket, &resource.name);
+            check_generation(
+                current.map_or(0, |(o, _)| o.generation),
+                req.spec.if_generation_match,
+                req.spec.if_generation_not_match,
+            )
+        };
+        check(&self.state.lock().unwrap())?;
+        let contents = collect(payload).await?;
+
+        let mut state = self.state.lock().unwrap();
+        check(&state)?;
+        let generation = state.generation + 1;
+        Ok(state.insert(resource, generation, contents))
+    }
+}
+
+impl State {
+    fn get(&self, bucket: &str, object: &str) -> Option<&(Object, Bytes)> {
+        self.objects.get(&(bucket.to_string(), object.to_string()))
+    }
+
+    fn insert(&mut self, resource: Object, generation: i64, contents: Bytes) -> Object {
+        self.generation = self.generation.max(generation);
+        let object = resource
+            .set_generation(generation)
+            .set_metageneration(1)
+            .set_size(contents.len() as i64)
+            .set_checksums(
+                ObjectChecksums::new()
+                    .set_crc32c(crc32c::crc32c(&contents))
+                    .set_md5_hash(Bytes::from_owner(md5::compute(&contents).0)),
+            );
+        self.objects.insert(
+            (object.bucket.clone(), object.name.clone()),
+            (object.clone(), contents),
+        );
+        object
+    }
+}
+
+impl gcs::stub::Storage for FakeStorage {
+    async fn read_object(
+        &self,
+        req: ReadObjectRequest,
+        _options: RequestOptions,
+    ) -> gcs::Result<ReadObjectResponse> {
+        let (object, contents) = {
+            let state = self.state.lock().unwrap();
+            state
+                .get(&req.bucket, &req.object)
+                .filter(|(o, _)| req.generation == 0 || o.generation == req.generation)
+                .cloned()
+                .ok_or_else(|| {
+                    error(
+                        Code::NotFound,
+                        format!(
+                            "{}/{} (generation={}) not found",
+                            req.bucket, req.object, req.generation
+                        ),
+                    )
+                })?
+        };
+        check_generation(
+            object.generation,
+            req.if_generation_match,
+            req.if_generation_not_match,
+        )?;
+
+        // 負のオフセットは、末尾からのバイト数を表す
+        let size = contents.len() as i64;
+        let start = match req.read_offset {
+            offset if offset < 0 => (size + offset).max(0),
+            offset if offset > size => {
+                return Err(error(
+                    Code::OutOfRange,
+                    format!("read_offset={offset} exceeds the object size {size}"),
+                ));
+            }
+            offset => offset,
+        };
+        let end = match req.read_limit {
+            limit if limit < 0 => {
+                return Err(error(
+                    Code::InvalidArgument,
+                    format!("read_limit={limit} must not be negative"),
+                ));
+            }
+            0 => size,
+            limit => (start + limit).min(size),
+        };
+
+        let mut highlights = ObjectHighlights::default();
+        highlights.generation = object.generation;
+        highlights.size = object.size;
+        highlights.checksums = object.checksums.clone();
+        Ok(ReadObjectResponse::from_source(
+            highlights,
+            contents.slice(start as usize..end as usize),
+        ))
+    }
+
+    async fn write_object_buffered<P>(
+        &self,
+        payload: P,
+        req: WriteObjectRequest,
+        _options: RequestOptions,
+    ) -> gcs::Result<Object>
+    where
+        P: StreamingSource + Send + Sync + 'static,
+    {
+        self.write(payload, req).await
+    }
+
+    async fn write_object_unbuffered<P>(
+        &self,
+        payload: P,
+        req: WriteObjectRequest,
+        _options: RequestOptions,
+    ) -> gcs::Result<Object>
+    where
+        P: StreamingSource + Seek + Send + Sync + 'static,
+    {
+        self.write(payload, req).await
+    }
+}
+
+// `if_generation_match`と`if_generation_not_match`を確認
+//
+// オブジェクトが存在しない場合の`current`は0で、`if_generation_match=0`はオブジェクトが存在しないことを表す。
+fn check_generation(
+    current: i64,
+    if_generation_match: Option<i64>,
+    if_generation_not_match: Option<i64>,
+) -> gcs::Result<()> {
+    if if_generation_match.is_some_and(|g| g != current)
+        || if_generation_not_match.is_some_and(|g| g == current)
+    {
+        return Err(error(
+            Code::FailedPrecondition,
+            format!(
+                "precondition failed, generation={current}, if_generation_match={if_generation_match:?}, if_generation_not_match={if_generation_not_match:?}"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// ペイロードをすべて読み込み
+pub(crate) async fn collect<P>(mut payload: P) -> gcs::Result<Bytes>
+where
+    P: StreamingSource,
+{
+    let mut contents = BytesMut::new();
+    while let Some(b) = payload.next().await.transpose().map_err(gcs::Error::ser)? {
+        contents.extend_from_slice(&b);
+    }
+    Ok(contents.freeze())
+}
+
+fn error(code: Code, message: String) -> gcs::Error {
+    gcs::Error::service(Status::default().set_code(code).set_message(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FakeStorage;
+    use gcs::client::Storage;
+    use gcs::model_ext::ReadRange;
+    use google_cloud_gax::error::rpc::Code;
+    use google_cloud_storage as gcs;
+
+    const BUCKET: &str = "projects/_/buckets/my-bucket";
+
+    async fn read(
+        client: &Storage<FakeStorage>,
+        range: ReadRange,
+        generation: i64,
+    ) -> gcs::Result<Vec<u8>> {
+        let mut reader = client
+            .read_object(BUCKET, "my-object")
+            .set_read_range(range)
+            .set_generation(generation)
+            .send()
+            .await?;
+        let mut contents = Vec::new();
+        while let Some(b) = reader.next().await.transpose()? {
+            contents.extend_from_slice(&b);
+        }
+        Ok(contents)
+    }
+
+    #[tokio::test]
+    async fn test_read_object() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let object = fake.insert(BUCKET, "my-object", "0123456789");
+        let client = Storage::from_stub(fake.clone());
+
+        assert_eq!(read(&client, ReadRange::all(), 0).await?, b"0123456789");
+        assert_eq!(read(&client, ReadRange::segment(2, 3), 0).await?, b"234");
+        assert_eq!(read(&client, ReadRange::offset(8), 0).await?, b"89");
+        assert_eq!(read(&client, ReadRange::tail(3), 0).await?, b"789");
+        assert_eq!(
+            read(&client, ReadRange::all(), object.generation).await?,
+            b"0123456789"
+        );
+
+        // 上書きされた世代は読み込めない
+        fake.insert(BUCKET, "my-object", "abc");
+        let err = read(&client, ReadRange::all(), object.generation)
+            .await
+            .expect_err("the generation was overwritten");
+        assert_eq!(err.status().map(|s| s.code), Some(Code::NotFound));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_object() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        // `if_generation_match=0`は、オブジェクトが存在しない場合のみ成功
+        let created = client
+            .write_object(BUCKET, "my-object", "hello")
+            .set_if_generation_match(0)
+            .send_buffered()
+            .await?;
+        assert_eq!(created.size, 5);
+        assert_eq!(fake.contents(BUCKET, "my-object").unwrap(), "hello");
+        let err = client
+            .write_object(BUCKET, "my-object", "world")
+            .set_if_generation_match(0)
+            .send_unbuffered()
+            .await
+            .expect_err("the object already exists");
+        assert_eq!(err.status().map(|s| s.code), Some(Code::FailedPrecondition));
+
+        // 現在の世代を指定した場合は上書きできる
+        let updated = client
+            .write_object(BUCKET, "my-object", "world")
+            .set_if_generation_match(created.generation)
+            .send_unbuffered()
+            .await?;
+        assert!(updated.generation > created.generation);
+        assert_eq!(fake.object(BUCKET, "my-object"), Some(updated));
+        assert_eq!(fake.contents(BUCKET, "my-object").unwrap(), "world");
+        Ok(())
+    }
+}
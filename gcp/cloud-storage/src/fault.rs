@@ -0,0 +1,417 @@
+//! # 障害を注入するスタブ
+//!
+//! `FaultInjector`は任意の`gcs::stub::Storage`の前に置いて、ルールに従ってリクエストに障害を注入する。
+//! 再試行や再開の処理を、GCSに接続せずにテストするために使用する。
+//!
+//! 確率で選択するルールはシードを指定した乱数生成器を使用するため、同じ順序のリクエストには同じ障害が注入される。
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use google_cloud_gax::error::rpc::{Code, Status};
+use google_cloud_storage as gcs;
+use google_cloud_storage::model::{Object, ReadObjectRequest};
+use google_cloud_storage::model_ext::WriteObjectRequest;
+use google_cloud_storage::read_object::ReadObjectResponse;
+use google_cloud_storage::request_options::RequestOptions;
+use google_cloud_storage::streaming_source::{Seek, StreamingSource};
+use rand::rngs::StdRng;
+use rand::{Rng as _, SeedableRng as _};
+
+/// 障害を注入するリクエストの種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// `read_object`
+    Read,
+    /// `write_object_buffered`と`write_object_unbuffered`
+    Write,
+}
+
+/// 障害を注入するリクエストを選択する条件
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trigger {
+    /// すべてのリクエスト
+    Always,
+    /// ルールに一致したN番目のリクエスト（1から数える）
+    Nth(usize),
+    /// 指定した確率で選択したリクエスト
+    Probability(f64),
+}
+
+/// 注入する障害
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// リクエストを`code`のエラーで失敗させる。
+    Error(Code),
+    /// `read_object`のレスポンスを、指定したバイト数を返した後に切断する。
+    ///
+    /// 書き込みのリクエストには影響しない。
+    Truncate(u64),
+    /// リクエストを指定した時間だけ遅延させる。
+    Delay(Duration),
+}
+
+/// 障害を注入するルール
+#[derive(Clone, Debug)]
+pub struct Rule {
+    operation: Operation,
+    trigger: Trigger,
+    fault: Fault,
+    limit: Option<usize>,
+}
+
+impl Rule {
+    /// `operation`のリクエストのうち、`trigger`に一致したリクエストに`fault`を注入するルールを作成する。
+    pub fn new(operation: Operation, trigger: Trigger, fault: Fault) -> Self {
+        Self {
+            operation,
+            trigger,
+            fault,
+            limit: None,
+        }
+    }
+
+    /// 障害を注入する回数の上限を設定する。
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// ルールに従って障害を注入するスタブ
+///
+/// クローンしたスタブはルールの状態を共有するため、`Storage::from_stub`に渡した後も注入した回数を確認できる。
+#[derive(Clone, Debug)]
+pub struct FaultInjector<T> {
+    inner: T,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    rules: Vec<RuleState>,
+    rng: StdRng,
+    injected: usize,
+}
+
+// ルールと、ルールに一致したリクエストの数と障害を注入した回数
+#[derive(Debug)]
+struct RuleState {
+    rule: Rule,
+    matched: usize,
+    injected: usize,
+}
+
+// 1つのリクエストに注入する障害
+#[derive(Debug, Default)]
+struct Injection {
+    delay: Duration,
+    error: Option<Code>,
+    truncate: Option<u64>,
+}
+
+impl<T> FaultInjector<T>
+where
+    T: gcs::stub::Storage,
+{
+    /// シード0の乱数生成器を使用するスタブを作成する。
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            state: Arc::new(Mutex::new(State {
+                rules: Vec::new(),
+                rng: StdRng::seed_from_u64(0),
+                injected: 0,
+            })),
+        }
+    }
+
+    /// 確率で選択するルールの乱数生成器のシードを設定する。
+    pub fn with_seed(self, seed: u64) -> Self {
+        self.state.lock().unwrap().rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// ルールを追加する。
+    ///
+    /// 1つのリクエストが複数のルールに一致した場合は、遅延は合計され、エラーは最初に追加したルールのものを返す。
+    pub fn with_rule(self, rule: Rule) -> Self {
+        self.state.lock().unwrap().rules.push(RuleState {
+            rule,
+            matched: 0,
+            injected: 0,
+        });
+        self
+    }
+
+    /// 障害を注入した回数を返す。
+    pub fn injected(&self) -> usize {
+        self.state.lock().unwrap().injected
+    }
+
+    // ルールを評価して、リクエストに注入する障害を決定
+    fn inject(&self, operation: Operation) -> Injection {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            rules,
+            rng,
+            injected,
+        } = &mut *state;
+        let mut injection = Injection::default();
+        for rule in rules.iter_mut().filter(|r| r.rule.operation == operation) {
+            rule.matched += 1;
+            let triggered = match rule.rule.trigger {
+                Trigger::Always => true,
+                Trigger::Nth(n) => rule.matched == n,
+                Trigger::Probability(p) => rng.random_bool(p),
+            };
+            if !triggered || rule.rule.limit.is_some_and(|l| rule.injected >= l) {
+                continue;
+            }
+            rule.injected += 1;
+            *injected += 1;
+            match rule.rule.fault {
+                Fault::Error(code) => injection.error = injection.error.or(Some(code)),
+                Fault::Truncate(n) => {
+                    injection.truncate = Some(injection.truncate.map_or(n, |t| t.min(n)));
+                }
+                Fault::Delay(d) => injection.delay += d,
+            }
+        }
+        injection
+    }
+}
+
+impl Injection {
+    // 遅延させた後に、エラーを注入する場合はエラーを返す
+    async fn apply(&self) -> gcs::Result<()> {
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        match self.error {
+            Some(code) => Err(gcs::Error::service(
+                Status::default()
+                    .set_code(code)
+                    .set_message("injected fault"),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T> gcs::stub::Storage for FaultInjector<T>
+where
+    T: gcs::stub::Storage,
+{
+    async fn read_object(
+        &self,
+        req: ReadObjectRequest,
+        options: RequestOptions,
+    ) -> gcs::Result<ReadObjectResponse> {
+        let injection = self.inject(Operation::Read);
+        injection.apply().await?;
+        let response = self.inner.read_object(req, options).await?;
+        match injection.truncate {
+            None => Ok(response),
+            Some(remaining) => Ok(ReadObjectResponse::from_source(
+                response.object(),
+                TruncatedSource {
+                    inner: Mutex::new(response),
+                    remaining,
+                    cut: false,
+                },
+            )),
+        }
+    }
+
+    async fn write_object_buffered<P>(
+        &self,
+        payload: P,
+        req: WriteObjectRequest,
+        options: RequestOptions,
+    ) -> gcs::Result<Object>
+    where
+        P: StreamingSource + Send + Sync + 'static,
+    {
+        self.inject(Operation::Write).apply().await?;
+        self.inner
+            .write_object_buffered(payload, req, options)
+            .await
+    }
+
+    async fn write_object_unbuffered<P>(
+        &self,
+        payload: P,
+        req: WriteObjectRequest,
+        options: RequestOptions,
+    ) -> gcs::Result<Object>
+    where
+        P: StreamingSource + Seek + Send + Sync + 'static,
+    {
+        self.inject(Operation::Write).apply().await?;
+        self.inner
+            .write_object_unbuffered(payload, req, options)
+            .await
+    }
+}
+
+// `remaining`バイトを返した後に切断するソース
+//
+// `ReadObjectResponse`は`Sync`ではないため、`Mutex`で包んで`get_mut`でアクセスする。
+#[derive(Debug)]
+struct TruncatedSource {
+    inner: Mutex<ReadObjectResponse>,
+    remaining: u64,
+    // チャンクの途中で切断した
+    cut: bool,
+}
+
+impl StreamingSource for TruncatedSource {
+    type Error = std::io::Error;
+
+    async fn next(&mut self) -> Option<Result<Bytes, Self::Error>> {
+        let reset = || {
+            std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "injected fault: the response was truncated",
+            )
+        };
+        if self.cut {
+            return Some(Err(reset()));
+        }
+        // 元のレスポンスが終わった場合は、切断せずに終了
+        let result = self.inner.get_mut().unwrap().next().await?;
+        if self.remaining == 0 {
+            return Some(Err(reset()));
+        }
+        match result {
+            Ok(mut b) => {
+                if b.len() as u64 > self.remaining {
+                    b.truncate(self.remaining as usize);
+                    self.cut = true;
+                }
+                self.remaining -= b.len() as u64;
+                Some(Ok(b))
+            }
+            Err(e) => Some(Err(std::io::Error::other(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Fault, FaultInjector, Operation, Rule, Trigger};
+    use crate::FakeStorage;
+    use gcs::client::Storage;
+    use google_cloud_gax::error::rpc::Code;
+    use google_cloud_storage as gcs;
+
+    const BUCKET: &str = "projects/_/buckets/my-bucket";
+
+    async fn read<T>(client: &Storage<T>) -> gcs::Result<Vec<u8>>
+    where
+        T: gcs::stub::Storage + 'static,
+    {
+        let mut reader = client.read_object(BUCKET, "my-object").send().await?;
+        let mut contents = Vec::new();
+        while let Some(b) = reader.next().await.transpose()? {
+            contents.extend_from_slice(&b);
+        }
+        Ok(contents)
+    }
+
+    fn fake() -> FakeStorage {
+        let fake = FakeStorage::new();
+        fake.insert(BUCKET, "my-object", "0123456789");
+        fake
+    }
+
+    #[tokio::test]
+    async fn test_nth() -> anyhow::Result<()> {
+        let injector = FaultInjector::new(fake()).with_rule(Rule::new(
+            Operation::Read,
+            Trigger::Nth(2),
+            Fault::Error(Code::Unavailable),
+        ));
+        let client = Storage::from_stub(injector.clone());
+
+        assert_eq!(read(&client).await?, b"0123456789");
+        let err = read(&client).await.expect_err("the 2nd read fails");
+        assert_eq!(err.status().map(|s| s.code), Some(Code::Unavailable));
+        assert_eq!(read(&client).await?, b"0123456789");
+        assert_eq!(injector.injected(), 1);
+
+        // 書き込みのリクエストには影響しない
+        client
+            .write_object(BUCKET, "my-object", "abc")
+            .send_buffered()
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_truncate() -> anyhow::Result<()> {
+        let injector = FaultInjector::new(fake()).with_rule(
+            Rule::new(Operation::Read, Trigger::Always, Fault::Truncate(4)).with_limit(1),
+        );
+        let client = Storage::from_stub(injector);
+
+        let mut reader = client.read_object(BUCKET, "my-object").send().await?;
+        let mut contents = Vec::new();
+        let err = loop {
+            match reader.next().await {
+                Some(Ok(b)) => contents.extend_from_slice(&b),
+                Some(Err(e)) => break e,
+                None => panic!("the response must be truncated"),
+            }
+        };
+        assert_eq!(contents, b"0123", "{err:?}");
+        // 上限に達した後は切断しない
+        assert_eq!(read(&client).await?, b"0123456789");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_probability() -> anyhow::Result<()> {
+        // 同じシードでは、同じリクエストが失敗する
+        let mut outcomes = Vec::new();
+        for _ in 0..2 {
+            let injector = FaultInjector::new(fake())
+                .with_seed(42)
+                .with_rule(Rule::new(
+                    Operation::Read,
+                    Trigger::Probability(0.5),
+                    Fault::Error(Code::Unavailable),
+                ));
+            let client = Storage::from_stub(injector);
+            let mut outcome = Vec::new();
+            for _ in 0..50 {
+                outcome.push(read(&client).await.is_ok());
+            }
+            outcomes.push(outcome);
+        }
+        assert_eq!(outcomes[0], outcomes[1]);
+        assert!(outcomes[0].contains(&true) && outcomes[0].contains(&false));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delay() -> anyhow::Result<()> {
+        let injector = FaultInjector::new(fake()).with_rule(Rule::new(
+            Operation::Write,
+            Trigger::Always,
+            Fault::Delay(Duration::from_millis(20)),
+        ));
+        let client = Storage::from_stub(injector);
+
+        let start = Instant::now();
+        client
+            .write_object(BUCKET, "my-object", "abc")
+            .send_unbuffered()
+            .await?;
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+//! 分割ダウンロードの進捗を記録するジャーナル
+//!
+//! ダウンロード先のファイルの隣に`{ファイル名}.journal`を作成して、次の形式で記録する。
+//!
+//! ```text
+//! generation=1700000000000000
+//! probe_size=8388608
+//! stripe_size=8388608
+//! done=0
+//! done=8388608
+//! ```
+//!
+//! `probe_size`は最初のストライプのサイズ、`stripe_size`は2番目以降のストライプのサイズである。
+//! `done`はダウンロードが完了したストライプのオフセットで、ストライプが完了するたびに追記する。
+//!
+//! オブジェクトの世代が`generation`と異なる場合は、ジャーナルを破棄して最初からダウンロードし直す。
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use tokio::io::AsyncWriteExt as _;
+use tokio::sync::Mutex;
+
+/// ジャーナルから読み込んだダウンロードの状態
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct JournalState {
+    pub generation: i64,
+    pub probe_size: u64,
+    pub stripe_size: u64,
+    pub completed: BTreeSet<u64>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: Mutex<tokio::fs::File>,
+}
+
+impl Journal {
+    /// ダウンロード先のファイルに対応するジャーナルのパス
+    pub fn path(destination: &Path) -> PathBuf {
+        let mut path = destination.as_os_str().to_owned();
+        path.push(".journal");
+        PathBuf::from(path)
+    }
+
+    /// 既存のジャーナルを読み込む。
+    ///
+    /// ジャーナルが存在しない場合は`None`を返す。
+    pub async fn load(destination: &Path) -> std::io::Result<Option<JournalState>> {
+        match tokio::fs::read_to_string(Self::path(destination)).await {
+            Ok(contents) => Ok(parse(&contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 新しいジャーナルを作成する。
+    pub async fn create(
+        destination: &Path,
+        generation: i64,
+        probe_size: u64,
+        stripe_size: u64,
+    ) -> std::io::Result<Self> {
+        let path = Self::path(destination);
+        let mut file = tokio::fs::File::create(&path).await?;
+        let header = format!(
+            "generation={generation}\nprobe_size={probe_size}\nstripe_size={stripe_size}\n"
+        );
+        file.write_all(header.as_bytes()).await?;
+        file.flush().await?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// 既存のジャーナルを開き、追記できるようにする。
+    pub async fn append(destination: &Path) -> std::io::Result<Self> {
+        let path = Self::path(destination);
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// ストライプのダウンロードが完了したことを記録する。
+    pub async fn record(&self, offset: u64) -> std::io::Result<()> {
+        let mut file = self.file.lock().await;
+        file.write_all(format!("done={offset}\n").as_bytes())
+            .await?;
+        file.flush().await
+    }
+
+    /// ダウンロードが完了したときに、ジャーナルを削除する。
+    pub async fn remove(self) -> std::io::Result<()> {
+        drop(self.file);
+        tokio::fs::remove_file(&self.path).await
+    }
+}
+
+// ジャーナルを解析
+//
+// 書き込み途中でプロセスが終了した場合に備えて、改行で終わっていない行と解析できない行は無視する。
+fn parse(contents: &str) -> Option<JournalState> {
+    let mut generation = None;
+    let mut probe_size = None;
+    let mut stripe_size = None;
+    let mut completed = BTreeSet::new();
+    for line in contents
+        .split_inclusive('\n')
+        .filter_map(|l| l.strip_suffix('\n'))
+    {
+        match line.split_once('=') {
+            Some(("generation", v)) => generation = v.parse().ok(),
+            Some(("probe_size", v)) => probe_size = v.parse().ok(),
+            Some(("stripe_size", v)) => stripe_size = v.parse().ok(),
+            Some(("done", v)) => {
+                if let Ok(offset) = v.parse() {
+                    completed.insert(offset);
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(JournalState {
+        generation: generation?,
+        probe_size: probe_size?,
+        stripe_size: stripe_size?,
+        completed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JournalState, parse};
+
+    #[test]
+    fn test_parse() {
+        let state =
+            parse("generation=42\nprobe_size=32\nstripe_size=64\ndone=0\ndone=96\n").unwrap();
+        assert_eq!(
+            state,
+            JournalState {
+                generation: 42,
+                probe_size: 32,
+                stripe_size: 64,
+                completed: [0, 96].into(),
+            }
+        );
+        // 書き込み途中の行は無視
+        let state = parse("generation=42\nprobe_size=64\nstripe_size=64\ndone=0\ndone=12").unwrap();
+        assert_eq!(state.completed, [0].into());
+        // ヘッダーがない場合は再開できない
+        assert_eq!(parse("done=0\n"), None);
+    }
+}
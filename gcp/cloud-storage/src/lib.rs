@@ -1,7 +1,58 @@
 use google_cloud_storage::client::StorageControl;
 use google_cloud_storage::model::Bucket;
-use google_cloud_storage::model::bucket::IamConfig;
-use google_cloud_storage::model::bucket::iam_config::UniformBucketLevelAccess;
+
+mod bulk;
+mod cancel;
+mod download;
+mod fake;
+mod fault;
+mod journal;
+mod queue;
+mod reader;
+mod record;
+mod rewrite;
+mod scan;
+mod spec;
+mod temp;
+mod transition;
+mod typed;
+mod upload;
+mod writer;
+
+pub use bulk::{
+    BulkTransfer, BulkTransferError, DEFAULT_MAX_TRANSFERS, TransferOutcome, TransferReport,
+    TransferResult,
+};
+pub use cancel::{CancelError, CancellableSource};
+pub use download::{
+    DEFAULT_MAX_IN_FLIGHT, DEFAULT_STRIPE_SIZE, DownloadError, DownloadProgress, DownloadReport,
+    ParallelDownloader, Tuning, Verification,
+};
+pub use fake::FakeStorage;
+pub use fault::{Fault, FaultInjector, Operation, Rule, Trigger};
+pub use queue::{QueueClosed, QueueError, QueueMetrics, QueueSender, QueueSource, queue};
+pub use reader::{ObjectReader, SeekableReader};
+pub use record::{ClientStub, Recorder, Replay};
+pub use rewrite::{
+    CheckpointStore, FileCheckpointStore, NoCheckpoint, RewriteDriver, RewriteProgress,
+};
+pub use scan::{CsvRecord, CsvRecords, Grep, Line, Lines, NdjsonRecords, ObjectScanner, ScanError};
+pub use spec::{BucketDiff, BucketSpec, LifecycleAction, LifecycleRule, SpecError, ensure_bucket};
+pub use temp::{
+    SweepFailure, SweepReport, TEMP_BUCKET_LABEL, TEMP_BUCKET_LABEL_VALUE, TempBucket,
+    empty_and_delete_bucket, is_orphaned, sweep_temp_buckets,
+};
+pub use transition::{
+    StorageClassTransitioner, Transition, TransitionResult, TransitionRule, evaluate_transition,
+    plan_transitions,
+};
+pub use typed::{
+    BINCODE_CONTENT_TYPE, EncodeError, JSON_CONTENT_TYPE, NDJSON_CONTENT_TYPE, write_bincode,
+    write_json, write_ndjson,
+};
+pub use upload::{DEFAULT_PART_SIZE, MAX_COMPOSE_SOURCES, ParallelUploader, UploadReport};
+
+pub use writer::{GcsWriter, WriterSource};
 
 pub const PROJECT_ID: &str = "gcp-for-rust";
 pub const BUCKET_NAME: &str = "my-bucket-6624150a-c3ca-491a-8071-0561f63e7a0b";
@@ -10,23 +61,15 @@ pub fn bucket_id(bucket_name: &str) -> String {
     format!("projects/_/buckets/{bucket_name}")
 }
 
+/// UBLAを有効にしたバケットを作成する。バケットがすでにある場合は、設定を合わせる。
 pub async fn create_bucket(
     control: &StorageControl,
     project_id: &str,
     bucket_name: &str,
 ) -> anyhow::Result<Bucket> {
-    let bucket = control
-        .create_bucket()
-        .set_parent("projects/_")
-        .set_bucket_id(bucket_name)
-        .set_bucket(
-            Bucket::new()
-                .set_project(format!("projects/{project_id}"))
-                .set_iam_config(IamConfig::new().set_uniform_bucket_level_access(
-                    UniformBucketLevelAccess::new().set_enabled(true),
-                )),
-        )
-        .send()
-        .await?;
-    Ok(bucket)
+    let spec = BucketSpec {
+        uniform_bucket_level_access: Some(true),
+        ..BucketSpec::default()
+    };
+    ensure_bucket(control, project_id, bucket_name, &spec).await
 }
@@ -44,12 +44,14 @@ fn main() {}
 #[cfg(test)]
 mod tests {
     use super::{count_newlines, upload};
+    use cloud_storage::{FakeStorage, Replay};
     use gcs::Result;
     use gcs::model::{Object, ReadObjectRequest};
     use gcs::model_ext::{ObjectHighlights, WriteObjectRequest};
     use gcs::read_object::ReadObjectResponse;
     use gcs::request_options::RequestOptions;
     use gcs::streaming_source::{BytesSource, Payload, Seek, StreamingSource};
+    use google_cloud_gax::error::rpc::Code;
     use google_cloud_storage as gcs;
 
     mockall::mock! {
@@ -128,4 +130,70 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_count_lines_fake() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let contents = String::from_iter((0..100).map(|i| format!("{i}\n")));
+        fake.insert_with_generation("projects/_/buckets/my-bucket", "my-object", 42, contents);
+        let client = gcs::client::Storage::from_stub(fake.clone());
+
+        let count = count_newlines(&client, "my-bucket", "my-object").await?;
+        assert_eq!(count, 100);
+
+        // 世代42が上書きされた場合は読み込めない
+        fake.insert("projects/_/buckets/my-bucket", "my-object", "0\n");
+        let err = count_newlines(&client, "my-bucket", "my-object")
+            .await
+            .expect_err("generation 42 no longer exists");
+        assert_eq!(err.status().map(|s| s.code), Some(Code::NotFound));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_fake() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = gcs::client::Storage::from_stub(fake.clone());
+
+        // 世代42のオブジェクトが存在しない場合は、前提条件を満たさない
+        let err = upload(&client, "my-bucket", "my-object")
+            .await
+            .expect_err("the object does not exist");
+        assert_eq!(err.status().map(|s| s.code), Some(Code::FailedPrecondition));
+
+        fake.insert_with_generation("projects/_/buckets/my-bucket", "my-object", 42, "old");
+        let object = upload(&client, "my-bucket", "my-object").await?;
+        assert!(object.generation > 42);
+        assert_eq!(
+            fake.contents("projects/_/buckets/my-bucket", "my-object")
+                .unwrap(),
+            "payload"
+        );
+
+        Ok(())
+    }
+
+    // `Recorder`の記録形式に合わせて手書きした合成のフィクスチャー（実際のプロジェクトの記録ではない）
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/mocking.json");
+
+    #[tokio::test]
+    async fn test_replay() -> anyhow::Result<()> {
+        let replay = Replay::load(FIXTURE).await?;
+        let client = gcs::client::Storage::from_stub(replay.clone());
+
+        let count = count_newlines(&client, "my-bucket", "my-object").await?;
+        assert_eq!(count, 100);
+
+        let object = upload(&client, "my-bucket", "my-object").await?;
+        assert_eq!(object.generation, 43);
+        // 2回目のアップロードは、世代が43に更新されているため前提条件を満たさない
+        let err = upload(&client, "my-bucket", "my-object")
+            .await
+            .expect_err("the generation is no longer 42");
+        assert_eq!(err.status().map(|s| s.code), Some(Code::FailedPrecondition));
+
+        assert_eq!(replay.remaining(), 0);
+        Ok(())
+    }
 }
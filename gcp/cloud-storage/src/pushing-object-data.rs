@@ -1,19 +1,7 @@
-use google_cloud_storage as gcs;
 use google_cloud_storage::client::Storage;
 use google_cloud_storage::client::StorageControl;
-use google_cloud_storage::streaming_source::StreamingSource;
-use tokio::sync::mpsc::{self, Receiver};
 
-#[derive(Debug)]
-struct QueueSource(Receiver<bytes::Bytes>);
-
-impl StreamingSource for QueueSource {
-    type Error = std::convert::Infallible;
-
-    async fn next(&mut self) -> Option<Result<bytes::Bytes, Self::Error>> {
-        self.0.recv().await.map(Ok)
-    }
-}
+use cloud_storage::{BucketSpec, ensure_bucket, queue};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -25,21 +13,16 @@ async fn main() -> anyhow::Result<()> {
 
     // バケットを作成
     let control = StorageControl::builder().build().await?;
-    let bucket = control
-        .create_bucket()
-        .set_parent("projects/_")
-        .set_bucket_id(bucket_id)
-        .set_bucket(gcs::model::Bucket::new().set_project(format!("projects/{project_id}")))
-        .send()
-        .await?;
+    let bucket = ensure_bucket(&control, &project_id, &bucket_id, &BucketSpec::default()).await?;
     println!("bucket successfully created {bucket:?}");
 
     let client = Storage::builder().build().await?;
 
     // データをプッシュしてオブジェクトを作成
-    let (sender, receiver) = mpsc::channel::<bytes::Bytes>(1024);
+    // キューに保持するデータは64KiBまでで、それを超える場合は`send`が待つ
+    let (sender, source) = queue(64 * 1024);
     let upload = client
-        .write_object(&bucket.name, object_name, QueueSource(receiver))
+        .write_object(&bucket.name, object_name, source)
         .send_buffered();
     let task = tokio::spawn(upload);
 
@@ -50,11 +33,17 @@ async fn main() -> anyhow::Result<()> {
             .await?;
     }
 
-    // 送信側をドロップして、チャネルを閉じる（受信側も閉じられる）
+    let metrics = sender.metrics();
+
+    // 送信側をドロップして、キューを閉じる（ソースも終わる）
     drop(sender);
 
     let object = task.await??;
     println!("object successfully uploaded {object:?}");
+    println!(
+        "{} bytes sent, blocked {} times for {:?} in total",
+        metrics.sent_bytes, metrics.blocked_sends, metrics.blocked
+    );
 
     Ok(())
 }
@@ -0,0 +1,348 @@
+//! # バイト数で容量を制限したプッシュ型のソース
+//!
+//! `mpsc::channel(1024)`はチャンクの数しか制限しないため、大きなチャンクを送るとメモリを使い過ぎる。
+//! `queue`はキューに保持するバイト数の上限を指定して、上限に達した場合は送信側を待たせる。
+//!
+//! ```ignore
+//! let (sender, source) = cloud_storage::queue(8 * 1024 * 1024);
+//! let task = tokio::spawn(client.write_object(bucket, "my-object", source).send_buffered());
+//! sender.send(bytes::Bytes::from_static(b"hello")).await?;
+//! drop(sender);
+//! let object = task.await??;
+//! ```
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use google_cloud_storage::streaming_source::StreamingSource;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{Semaphore, TryAcquireError};
+
+/// キューに保持するバイト数の上限が`capacity`の送信側とソースを返す。
+///
+/// `capacity`より大きいチャンクも送れるが、キューが空になるまで待つ。
+/// すべての送信側をドロップするとソースが終わり、アップロードが完了する。
+pub fn queue(capacity: usize) -> (QueueSender, QueueSource) {
+    assert!(capacity > 0, "capacity must be positive");
+    // セマフォの許可の数は`u32`で取得するため、容量を制限
+    let capacity = capacity.min(u32::MAX as usize);
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let shared = Arc::new(Shared {
+        capacity,
+        budget: Semaphore::new(capacity),
+        sent_bytes: AtomicU64::new(0),
+        blocked_sends: AtomicU64::new(0),
+        blocked_nanos: AtomicU64::new(0),
+    });
+    (
+        QueueSender {
+            sender,
+            shared: shared.clone(),
+        },
+        QueueSource { receiver, shared },
+    )
+}
+
+/// キューの統計情報
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueMetrics {
+    /// 送信したバイト数
+    pub sent_bytes: u64,
+    /// キューに保持しているバイト数
+    pub buffered_bytes: u64,
+    /// 容量が空くのを待った送信の数
+    pub blocked_sends: u64,
+    /// 送信側が容量が空くのを待った時間の合計
+    pub blocked: Duration,
+}
+
+/// キューの送信側
+///
+/// クローンした送信側は、同じキューに送信する。
+#[derive(Clone, Debug)]
+pub struct QueueSender {
+    sender: UnboundedSender<Item>,
+    shared: Arc<Shared>,
+}
+
+impl QueueSender {
+    /// チャンクを送信する。キューの容量が空くまで待つ。
+    ///
+    /// アップロードが終了した場合は、送信できなかったチャンクをエラーとして返す。
+    pub async fn send(&self, chunk: Bytes) -> Result<(), QueueClosed> {
+        let permits = chunk.len().min(self.shared.capacity) as u32;
+        let permit = match self.shared.budget.try_acquire_many(permits) {
+            Ok(permit) => permit,
+            Err(TryAcquireError::Closed) => return Err(QueueClosed(chunk)),
+            Err(TryAcquireError::NoPermits) => {
+                let start = Instant::now();
+                let permit = self.shared.budget.acquire_many(permits).await;
+                self.shared.blocked_sends.fetch_add(1, Ordering::Relaxed);
+                self.shared
+                    .blocked_nanos
+                    .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                match permit {
+                    Ok(permit) => permit,
+                    Err(_) => return Err(QueueClosed(chunk)),
+                }
+            }
+        };
+        let len = chunk.len() as u64;
+        match self.sender.send(Item::Chunk(chunk, permits)) {
+            Ok(()) => {
+                // ソースがチャンクを受け取った時に、容量を戻す
+                permit.forget();
+                self.shared.sent_bytes.fetch_add(len, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(mpsc::error::SendError(item)) => match item {
+                Item::Chunk(chunk, _) => Err(QueueClosed(chunk)),
+                Item::Abort(_) => unreachable!("a chunk was sent"),
+            },
+        }
+    }
+
+    /// アップロードを中止する。
+    ///
+    /// ソースは送信済みのチャンクを返した後に`QueueError::Aborted`を返す。
+    pub fn abort(&self, error: impl Into<Box<dyn Error + Send + Sync>>) {
+        let _ = self.sender.send(Item::Abort(error.into()));
+    }
+
+    /// キューの統計情報を返す。
+    pub fn metrics(&self) -> QueueMetrics {
+        self.shared.metrics()
+    }
+}
+
+/// 送信側から受け取ったチャンクを返すソース
+#[derive(Debug)]
+pub struct QueueSource {
+    receiver: UnboundedReceiver<Item>,
+    shared: Arc<Shared>,
+}
+
+impl QueueSource {
+    /// キューの統計情報を返す。
+    pub fn metrics(&self) -> QueueMetrics {
+        self.shared.metrics()
+    }
+}
+
+impl StreamingSource for QueueSource {
+    type Error = QueueError;
+
+    async fn next(&mut self) -> Option<Result<Bytes, Self::Error>> {
+        match self.receiver.recv().await? {
+            Item::Chunk(chunk, permits) => {
+                self.shared.budget.add_permits(permits as usize);
+                Some(Ok(chunk))
+            }
+            Item::Abort(e) => {
+                // 以降の送信を失敗させる
+                self.receiver.close();
+                self.shared.budget.close();
+                Some(Err(QueueError::Aborted(e)))
+            }
+        }
+    }
+}
+
+impl Drop for QueueSource {
+    fn drop(&mut self) {
+        // 容量が空くのを待っている送信側を起こす
+        self.shared.budget.close();
+    }
+}
+
+/// ソースのエラー
+#[derive(Debug)]
+pub enum QueueError {
+    /// 送信側がアップロードを中止した
+    Aborted(Box<dyn Error + Send + Sync>),
+}
+
+impl Error for QueueError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Aborted(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+impl Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Aborted(e) => write!(f, "the upload was aborted by the producer: {e}"),
+        }
+    }
+}
+
+/// アップロードが終了したため送信できなかったチャンク
+#[derive(Debug)]
+pub struct QueueClosed(pub Bytes);
+
+impl Error for QueueClosed {}
+
+impl Display for QueueClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the upload is no longer receiving data")
+    }
+}
+
+#[derive(Debug)]
+enum Item {
+    // チャンクと、チャンクが使用している容量
+    Chunk(Bytes, u32),
+    Abort(Box<dyn Error + Send + Sync>),
+}
+
+#[derive(Debug)]
+struct Shared {
+    capacity: usize,
+    budget: Semaphore,
+    sent_bytes: AtomicU64,
+    blocked_sends: AtomicU64,
+    blocked_nanos: AtomicU64,
+}
+
+impl Shared {
+    fn metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            sent_bytes: self.sent_bytes.load(Ordering::Relaxed),
+            buffered_bytes: self
+                .capacity
+                .saturating_sub(self.budget.available_permits()) as u64,
+            blocked_sends: self.blocked_sends.load(Ordering::Relaxed),
+            blocked: Duration::from_nanos(self.blocked_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueueError, queue};
+    use crate::FakeStorage;
+    use bytes::Bytes;
+    use gcs::client::Storage;
+    use gcs::streaming_source::StreamingSource as _;
+    use google_cloud_storage as gcs;
+    use std::error::Error as _;
+    use std::time::Duration;
+
+    const BUCKET: &str = "projects/_/buckets/my-bucket";
+
+    #[tokio::test]
+    async fn test_capacity() -> anyhow::Result<()> {
+        let (sender, mut source) = queue(10);
+        sender.send(Bytes::from_static(b"0123")).await?;
+        sender.send(Bytes::from_static(b"4567")).await?;
+        assert_eq!(source.metrics().buffered_bytes, 8);
+
+        // 容量を超えるため、ソースが受け取るまで待つ
+        let blocked = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.send(Bytes::from_static(b"89ab")).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+        assert_eq!(source.next().await.transpose()?.unwrap(), "0123");
+        blocked.await??;
+
+        // 容量より大きいチャンクは、キューが空になってから送る
+        let oversized = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.send(Bytes::from(vec![0; 100])).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!oversized.is_finished());
+        assert_eq!(source.next().await.transpose()?.unwrap(), "4567");
+        assert_eq!(source.next().await.transpose()?.unwrap(), "89ab");
+        oversized.await??;
+        assert_eq!(source.next().await.transpose()?.unwrap().len(), 100);
+
+        let metrics = sender.metrics();
+        assert_eq!(metrics.sent_bytes, 112);
+        assert_eq!(metrics.buffered_bytes, 0);
+        assert_eq!(metrics.blocked_sends, 2);
+        assert!(metrics.blocked >= Duration::from_millis(50), "{metrics:?}");
+
+        // すべての送信側をドロップするとソースが終わる
+        drop(sender);
+        assert!(source.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        let (sender, source) = queue(64);
+        let upload = tokio::spawn(
+            client
+                .write_object(BUCKET, "my-object", source)
+                .send_buffered(),
+        );
+        for _ in 0..100 {
+            sender
+                .send(Bytes::from_static(
+                    b"I will not write funny examples in class\n",
+                ))
+                .await?;
+        }
+        drop(sender);
+        let object = upload.await??;
+        assert_eq!(object.size, 4100);
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct MyError;
+
+    impl std::error::Error for MyError {}
+
+    impl std::fmt::Display for MyError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "the producer failed")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_abort() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        let (sender, source) = queue(64);
+        let upload = tokio::spawn(
+            client
+                .write_object(BUCKET, "my-object", source)
+                .send_buffered(),
+        );
+        sender.send(Bytes::from_static(b"partial")).await?;
+        sender.abort(MyError);
+
+        let err = upload.await?.expect_err("the upload was aborted");
+        assert!(err.is_serialization(), "{err:?}");
+        let source = err
+            .source()
+            .and_then(|e| e.downcast_ref::<QueueError>())
+            .expect("the source is QueueError");
+        assert!(
+            source.source().is_some_and(|e| e.is::<MyError>()),
+            "{source:?}"
+        );
+        assert!(fake.object(BUCKET, "my-object").is_none());
+
+        // 中止した後は送信できない
+        let err = sender
+            .send(Bytes::from_static(b"more"))
+            .await
+            .expect_err("the upload was aborted");
+        assert_eq!(err.0, "more");
+        Ok(())
+    }
+}
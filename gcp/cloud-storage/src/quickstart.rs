@@ -1,7 +1,6 @@
 //! ```sh
 //! cargo run --package=cloud-storage --bin=quickstart -- <project-id>
 //! ```
-use google_cloud_storage as gcs;
 use google_cloud_storage::client::{Storage, StorageControl};
 
 #[tokio::main]
@@ -10,22 +9,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let bucket_id = format!("my-bucket-{}", uuid::Uuid::new_v4());
 
     let control = StorageControl::builder().build().await?;
-    let bucket = control
-        .create_bucket()
-        .set_parent("projects/_")
-        .set_bucket_id(bucket_id)
-        .set_bucket(
-            gcs::model::Bucket::new()
-                .set_project(format!("projects/{project_id}"))
-                .set_iam_config(
-                    gcs::model::bucket::IamConfig::new().set_uniform_bucket_level_access(
-                        gcs::model::bucket::iam_config::UniformBucketLevelAccess::new()
-                            .set_enabled(true),
-                    ),
-                ),
-        )
-        .send()
-        .await?;
+    let bucket = cloud_storage::create_bucket(&control, &project_id, &bucket_id).await?;
     println!("bucket successfully created {bucket:?}");
 
     let client = Storage::builder().build().await?;
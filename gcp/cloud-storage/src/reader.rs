@@ -0,0 +1,301 @@
+//! # `AsyncRead`でオブジェクトを読み込むアダプター
+//!
+//! `ObjectReader`は`ReadObjectResponse`を`AsyncRead`と`AsyncBufRead`として公開する。
+//! デコーダーや`tokio::io::copy`、`AsyncBufReadExt::lines`にそのまま渡せる。
+//!
+//! `SeekableReader`は`AsyncSeek`も実装して、シークした位置から新しい範囲読み込みを発行する。
+//! 読み込み中にオブジェクトが上書きされても内容が混ざらないように、最初に読み込んだ世代を指定して読み込む。
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use google_cloud_storage as gcs;
+use google_cloud_storage::client::Storage;
+use google_cloud_storage::model_ext::{ObjectHighlights, ReadRange};
+use google_cloud_storage::read_object::ReadObjectResponse;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncSeek, ReadBuf};
+use tokio_util::io::StreamReader;
+
+/// `ReadObjectResponse`を読み込む`AsyncRead`
+///
+/// 読み込みのエラーは、`gcs::Error`を内部のエラーとした`io::Error`として返す。
+pub struct ObjectReader {
+    inner: StreamReader<BoxStream<'static, io::Result<Bytes>>, Bytes>,
+    highlights: ObjectHighlights,
+}
+
+impl ObjectReader {
+    pub fn new(reader: ReadObjectResponse) -> Self {
+        let highlights = reader.object();
+        let chunks = futures::stream::unfold(reader, |mut reader| async move {
+            let chunk = reader.next().await?.map_err(io::Error::other);
+            Some((chunk, reader))
+        });
+        Self {
+            inner: StreamReader::new(Box::pin(chunks)),
+            highlights,
+        }
+    }
+
+    /// 読み込んでいるオブジェクトのメタデータを返す。
+    pub fn object(&self) -> &ObjectHighlights {
+        &self.highlights
+    }
+}
+
+impl AsyncRead for ObjectReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for ObjectReader {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().inner).consume(amt)
+    }
+}
+
+/// シークできる`AsyncRead`
+///
+/// シークした後の最初の読み込みで、シークした位置からの範囲読み込みを発行する。
+pub struct SeekableReader<'a, T>
+where
+    T: gcs::stub::Storage + 'static,
+{
+    client: &'a Storage<T>,
+    bucket: String,
+    object: String,
+    generation: i64,
+    size: u64,
+    position: u64,
+    reader: Option<ObjectReader>,
+    opening: Option<BoxFuture<'a, gcs::Result<ReadObjectResponse>>>,
+}
+
+impl<'a, T> SeekableReader<'a, T>
+where
+    T: gcs::stub::Storage + 'static,
+{
+    /// オブジェクトを先頭から読み込む。
+    ///
+    /// `bucket`は`projects/_/buckets/{bucket_name}`形式のバケット名を指定する。
+    pub async fn open(
+        client: &'a Storage<T>,
+        bucket: impl Into<String>,
+        object: impl Into<String>,
+    ) -> gcs::Result<Self> {
+        let bucket = bucket.into();
+        let object = object.into();
+        let reader = ObjectReader::new(client.read_object(&bucket, &object).send().await?);
+        Ok(Self {
+            client,
+            bucket,
+            object,
+            generation: reader.object().generation,
+            size: reader.object().size as u64,
+            position: 0,
+            reader: Some(reader),
+            opening: None,
+        })
+    }
+
+    /// 読み込んでいるオブジェクトの世代を返す。
+    pub fn generation(&self) -> i64 {
+        self.generation
+    }
+
+    /// オブジェクトのサイズを返す。
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// 現在の位置を返す。
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<T> AsyncBufRead for SeekableReader<'_, T>
+where
+    T: gcs::stub::Storage + 'static,
+{
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.position >= this.size {
+            return Poll::Ready(Ok(&[]));
+        }
+        if this.reader.is_none() {
+            let opening = this.opening.get_or_insert_with(|| {
+                let builder = this
+                    .client
+                    .read_object(&this.bucket, &this.object)
+                    .set_generation(this.generation)
+                    .set_read_range(ReadRange::offset(this.position));
+                Box::pin(builder.send())
+            });
+            let response = ready!(opening.as_mut().poll(cx));
+            this.opening = None;
+            this.reader = Some(ObjectReader::new(response.map_err(io::Error::other)?));
+        }
+        let reader = this.reader.as_mut().expect("the reader is opened");
+        Pin::new(reader).poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        if let Some(reader) = this.reader.as_mut() {
+            Pin::new(reader).consume(amt);
+            this.position += amt as u64;
+        }
+    }
+}
+
+impl<T> AsyncRead for SeekableReader<'_, T>
+where
+    T: gcs::stub::Storage + 'static,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let available = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let n = available.len().min(buf.remaining());
+        buf.put_slice(&available[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> AsyncSeek for SeekableReader<'_, T>
+where
+    T: gcs::stub::Storage + 'static,
+{
+    fn start_seek(self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let target = match position {
+            io::SeekFrom::Start(n) => Some(n),
+            io::SeekFrom::Current(n) => this.position.checked_add_signed(n),
+            io::SeekFrom::End(n) => this.size.checked_add_signed(n),
+        };
+        let Some(target) = target else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot seek to a negative position",
+            ));
+        };
+        if target != this.position {
+            // 次の読み込みで、新しい位置から読み込む
+            this.position = target;
+            this.reader = None;
+            this.opening = None;
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ObjectReader, SeekableReader};
+    use crate::FakeStorage;
+    use gcs::client::Storage;
+    use google_cloud_gax::error::rpc::Code;
+    use google_cloud_storage as gcs;
+    use std::io::SeekFrom;
+    use tokio::io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncSeekExt as _};
+
+    const BUCKET: &str = "projects/_/buckets/my-bucket";
+
+    #[tokio::test]
+    async fn test_object_reader() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        fake.insert(BUCKET, "my-object", "first\nsecond\nthird\n");
+        let client = Storage::from_stub(fake.clone());
+
+        let response = client.read_object(BUCKET, "my-object").send().await?;
+        let reader = ObjectReader::new(response);
+        assert_eq!(reader.object().size, 19);
+        let mut lines = reader.lines();
+        let mut actual = Vec::new();
+        while let Some(line) = lines.next_line().await? {
+            actual.push(line);
+        }
+        assert_eq!(actual, vec!["first", "second", "third"]);
+
+        let response = client.read_object(BUCKET, "my-object").send().await?;
+        let mut contents = Vec::new();
+        tokio::io::copy(&mut ObjectReader::new(response), &mut contents).await?;
+        assert_eq!(contents, b"first\nsecond\nthird\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_seekable_reader() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let object = fake.insert(BUCKET, "my-object", "0123456789");
+        let client = Storage::from_stub(fake.clone());
+
+        let mut reader = SeekableReader::open(&client, BUCKET, "my-object").await?;
+        assert_eq!(reader.generation(), object.generation);
+        assert_eq!(reader.size(), 10);
+
+        let mut buf = [0; 3];
+        reader.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"012");
+        assert_eq!(reader.seek(SeekFrom::Start(5)).await?, 5);
+        reader.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"567");
+        assert_eq!(reader.seek(SeekFrom::Current(-4)).await?, 4);
+        reader.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"456");
+        assert_eq!(reader.seek(SeekFrom::End(-2)).await?, 8);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await?;
+        assert_eq!(rest, b"89");
+
+        // 末尾より後ろは空、先頭より前はエラー
+        reader.seek(SeekFrom::Start(20)).await?;
+        assert_eq!(reader.read(&mut buf).await?, 0);
+        let err = reader.seek(SeekFrom::End(-11)).await.expect_err("negative");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_seekable_reader_overwritten() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        fake.insert(BUCKET, "my-object", "0123456789");
+        let client = Storage::from_stub(fake.clone());
+
+        let mut reader = SeekableReader::open(&client, BUCKET, "my-object").await?;
+        fake.insert(BUCKET, "my-object", "abcdefghij");
+
+        // シークした後は、最初に読み込んだ世代を読み込もうとして失敗する
+        reader.seek(SeekFrom::Start(5)).await?;
+        let err = reader
+            .read_u8()
+            .await
+            .expect_err("the generation was overwritten");
+        let err = err
+            .into_inner()
+            .and_then(|e| e.downcast::<gcs::Error>().ok())
+            .expect("the source is gcs::Error");
+        assert_eq!(err.status().map(|s| s.code), Some(Code::NotFound));
+        Ok(())
+    }
+}
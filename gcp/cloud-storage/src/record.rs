@@ -0,0 +1,769 @@
+//! # リクエストとレスポンスの記録と再生
+//!
+//! `Recorder`は任意の`gcs::stub::Storage`の前に置いて、リクエストとレスポンスをフィクスチャーとして記録する。
+//! `Replay`は記録したフィクスチャーを読み込み、同じリクエストに記録したレスポンスを返す。
+//!
+//! 実際のプロジェクトに対して一度だけ記録すれば、以降のテストはGCSに接続せずに実行できる。
+//!
+//! ```ignore
+//! // 記録
+//! let client = Storage::builder().build().await?;
+//! let recorder = Recorder::new(ClientStub::new(client));
+//! count_newlines(&Storage::from_stub(recorder.clone()), "my-bucket", "my-object").await?;
+//! recorder.save("fixtures/count-newlines.json").await?;
+//!
+//! // 再生
+//! let replay = Replay::load("fixtures/count-newlines.json").await?;
+//! count_newlines(&Storage::from_stub(replay), "my-bucket", "my-object").await?;
+//! ```
+//!
+//! フィクスチャーはJSONで、オブジェクトの内容はBase64で記録する。
+//! エラーはステータスコードとメッセージのみを記録して、再生するときは`Error::service`として返す。
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bytes::{Bytes, BytesMut};
+use google_cloud_gax::error::rpc::{Code, Status};
+use google_cloud_storage as gcs;
+use google_cloud_storage::builder::storage::WriteObject;
+use google_cloud_storage::client::Storage;
+use google_cloud_storage::model::{
+    CommonObjectRequestParams, Object, ObjectChecksums, ReadObjectRequest, WriteObjectSpec,
+};
+use google_cloud_storage::model_ext::{ObjectHighlights, ReadRange, WriteObjectRequest};
+use google_cloud_storage::read_object::ReadObjectResponse;
+use google_cloud_storage::request_options::RequestOptions;
+use google_cloud_storage::streaming_source::{Payload, Seek, StreamingSource};
+use serde::{Deserialize, Serialize};
+
+use crate::fake::collect;
+
+/// 実際のクライアントにリクエストを転送するスタブ
+///
+/// `Recorder`で実際のGCSとの通信を記録するために使用する。
+#[derive(Clone, Debug)]
+pub struct ClientStub {
+    client: Storage,
+}
+
+impl ClientStub {
+    pub fn new(client: Storage) -> Self {
+        Self { client }
+    }
+}
+
+impl gcs::stub::Storage for ClientStub {
+    async fn read_object(
+        &self,
+        req: ReadObjectRequest,
+        _options: RequestOptions,
+    ) -> gcs::Result<ReadObjectResponse> {
+        let range = match (req.read_offset, req.read_limit) {
+            (offset, _) if offset < 0 => ReadRange::tail(offset.unsigned_abs()),
+            (offset, 0) => ReadRange::offset(offset as u64),
+            (offset, limit) => ReadRange::segment(offset as u64, limit as u64),
+        };
+        let mut builder = self
+            .client
+            .read_object(req.bucket, req.object)
+            .set_read_range(range);
+        if req.generation != 0 {
+            builder = builder.set_generation(req.generation);
+        }
+        if let Some(generation) = req.if_generation_match {
+            builder = builder.set_if_generation_match(generation);
+        }
+        if let Some(generation) = req.if_generation_not_match {
+            builder = builder.set_if_generation_not_match(generation);
+        }
+        builder.send().await
+    }
+
+    async fn write_object_buffered<P>(
+        &self,
+        payload: P,
+        req: WriteObjectRequest,
+        _options: RequestOptions,
+    ) -> gcs::Result<Object>
+    where
+        P: StreamingSource + Send + Sync + 'static,
+    {
+        self.write(req, collect(payload).await?, true).await
+    }
+
+    async fn write_object_unbuffered<P>(
+        &self,
+        payload: P,
+        req: WriteObjectRequest,
+        _options: RequestOptions,
+    ) -> gcs::Result<Object>
+    where
+        P: StreamingSource + Seek + Send + Sync + 'static,
+    {
+        self.write(req, collect(payload).await?, false).await
+    }
+}
+
+impl ClientStub {
+    async fn write(
+        &self,
+        req: WriteObjectRequest,
+        contents: Bytes,
+        buffered: bool,
+    ) -> gcs::Result<Object> {
+        let resource = req.spec.resource.clone().unwrap_or_default();
+        let builder = forward(
+            self.client
+                .write_object(resource.bucket, resource.name, contents),
+            req.spec,
+            req.params,
+        )?;
+        if buffered {
+            builder.send_buffered().await
+        } else {
+            builder.send_unbuffered().await
+        }
+    }
+}
+
+// 書き込みリクエストの指定をすべてビルダーに設定する
+// ビルダーで再現できない顧客指定の暗号鍵と追記可能なオブジェクトは、黙って無視せずにエラーにする
+fn forward<T, S>(
+    builder: WriteObject<T, S>,
+    spec: WriteObjectSpec,
+    params: Option<CommonObjectRequestParams>,
+) -> gcs::Result<WriteObject<T, S>>
+where
+    S: gcs::stub::Storage + 'static,
+{
+    if params.is_some() {
+        return Err(gcs::Error::ser(
+            "customer-supplied encryption keys are not supported by ClientStub",
+        ));
+    }
+    if spec.appendable == Some(true) {
+        return Err(gcs::Error::ser(
+            "appendable objects are not supported by ClientStub",
+        ));
+    }
+    let resource = spec.resource.unwrap_or_default();
+    let mut builder = builder
+        .set_acl(resource.acl)
+        .set_cache_control(resource.cache_control)
+        .set_content_disposition(resource.content_disposition)
+        .set_content_encoding(resource.content_encoding)
+        .set_content_language(resource.content_language)
+        .set_content_type(resource.content_type)
+        .set_metadata(resource.metadata)
+        .set_storage_class(resource.storage_class)
+        .set_temporary_hold(resource.temporary_hold)
+        .set_kms_key(resource.kms_key)
+        .set_predefined_acl(spec.predefined_acl);
+    if let Some(time) = resource.custom_time {
+        builder = builder.set_custom_time(time);
+    }
+    if let Some(hold) = resource.event_based_hold {
+        builder = builder.set_event_based_hold(hold);
+    }
+    if let Some(retention) = resource.retention {
+        builder = builder.set_retention(retention);
+    }
+    if let Some(contexts) = resource.contexts {
+        builder = builder.set_contexts(contexts);
+    }
+    if let Some(checksums) = resource.checksums {
+        if let Some(crc32c) = checksums.crc32c {
+            builder = builder.with_known_crc32c(crc32c);
+        }
+        if !checksums.md5_hash.is_empty() {
+            builder = builder.with_known_md5_hash(checksums.md5_hash);
+        }
+    }
+    if let Some(generation) = spec.if_generation_match {
+        builder = builder.set_if_generation_match(generation);
+    }
+    if let Some(generation) = spec.if_generation_not_match {
+        builder = builder.set_if_generation_not_match(generation);
+    }
+    if let Some(metageneration) = spec.if_metageneration_match {
+        builder = builder.set_if_metageneration_match(metageneration);
+    }
+    if let Some(metageneration) = spec.if_metageneration_not_match {
+        builder = builder.set_if_metageneration_not_match(metageneration);
+    }
+    Ok(builder)
+}
+
+/// リクエストとレスポンスを記録するスタブ
+///
+/// クローンしたスタブは記録を共有するため、`Storage::from_stub`に渡した後に`save`で保存できる。
+#[derive(Clone, Debug)]
+pub struct Recorder<T> {
+    inner: T,
+    interactions: Arc<Mutex<Vec<Interaction>>>,
+}
+
+impl<T> Recorder<T>
+where
+    T: gcs::stub::Storage,
+{
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            interactions: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// 記録したリクエストとレスポンスを、フィクスチャーとして保存する。
+    pub async fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let fixture = Fixture {
+            interactions: self.interactions.lock().unwrap().clone(),
+        };
+        tokio::fs::write(path, serde_json::to_vec_pretty(&fixture)?).await?;
+        Ok(())
+    }
+
+    fn record(&self, interaction: Interaction) {
+        self.interactions.lock().unwrap().push(interaction);
+    }
+}
+
+impl<T> gcs::stub::Storage for Recorder<T>
+where
+    T: gcs::stub::Storage,
+{
+    async fn read_object(
+        &self,
+        req: ReadObjectRequest,
+        options: RequestOptions,
+    ) -> gcs::Result<ReadObjectResponse> {
+        let request = ReadRequest::from(&req);
+        // レスポンスを記録するために、ストリームをすべて読み込む
+        let result: gcs::Result<ReadResponse> = async {
+            let mut response = self.inner.read_object(req, options).await?;
+            let highlights = response.object();
+            let mut contents = BytesMut::new();
+            while let Some(b) = response.next().await.transpose()? {
+                contents.extend_from_slice(&b);
+            }
+            Ok(ReadResponse::new(&highlights, contents.freeze()))
+        }
+        .await;
+        self.record(Interaction::Read {
+            request,
+            response: result.as_ref().cloned().map_err(Into::into),
+        });
+        result.map(ReadResponse::into_response)
+    }
+
+    async fn write_object_buffered<P>(
+        &self,
+        payload: P,
+        req: WriteObjectRequest,
+        options: RequestOptions,
+    ) -> gcs::Result<Object>
+    where
+        P: StreamingSource + Send + Sync + 'static,
+    {
+        let contents = collect(payload).await?;
+        let request = WriteRequest::new(&req, contents.clone());
+        let result = self
+            .inner
+            .write_object_buffered(Payload::from(contents), req, options)
+            .await;
+        self.record(Interaction::Write {
+            request,
+            response: result.as_ref().map(Into::into).map_err(Into::into),
+        });
+        result
+    }
+
+    async fn write_object_unbuffered<P>(
+        &self,
+        payload: P,
+        req: WriteObjectRequest,
+        options: RequestOptions,
+    ) -> gcs::Result<Object>
+    where
+        P: StreamingSource + Seek + Send + Sync + 'static,
+    {
+        let contents = collect(payload).await?;
+        let request = WriteRequest::new(&req, contents.clone());
+        let result = self
+            .inner
+            .write_object_unbuffered(Payload::from(contents), req, options)
+            .await;
+        self.record(Interaction::Write {
+            request,
+            response: result.as_ref().map(Into::into).map_err(Into::into),
+        });
+        result
+    }
+}
+
+/// 記録したフィクスチャーのレスポンスを返すスタブ
+///
+/// リクエストは記録した順序ではなく内容で照合するため、並行して発行されたリクエストも再生できる。
+/// 同じ内容のリクエストが複数記録されている場合は、記録した順に返す。
+#[derive(Clone, Debug)]
+pub struct Replay {
+    interactions: Arc<Mutex<Vec<Option<Interaction>>>>,
+}
+
+impl Replay {
+    /// フィクスチャーを読み込む。
+    pub async fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let fixture: Fixture = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+        Ok(Self {
+            interactions: Arc::new(Mutex::new(
+                fixture.interactions.into_iter().map(Some).collect(),
+            )),
+        })
+    }
+
+    /// まだ再生していないレスポンスの数を返す。
+    pub fn remaining(&self) -> usize {
+        let interactions = self.interactions.lock().unwrap();
+        interactions.iter().filter(|i| i.is_some()).count()
+    }
+
+    // 条件に一致する最初の記録を取り出す
+    fn take<F>(&self, f: F) -> Option<Interaction>
+    where
+        F: Fn(&Interaction) -> bool,
+    {
+        let mut interactions = self.interactions.lock().unwrap();
+        interactions
+            .iter_mut()
+            .find(|i| i.as_ref().is_some_and(&f))
+            .and_then(Option::take)
+    }
+}
+
+impl gcs::stub::Storage for Replay {
+    async fn read_object(
+        &self,
+        req: ReadObjectRequest,
+        _options: RequestOptions,
+    ) -> gcs::Result<ReadObjectResponse> {
+        let request = ReadRequest::from(&req);
+        match self.take(|i| matches!(i, Interaction::Read { request: r, .. } if *r == request)) {
+            Some(Interaction::Read { response, .. }) => response
+                .map(ReadResponse::into_response)
+                .map_err(Into::into),
+            _ => Err(unrecorded(&request)),
+        }
+    }
+
+    async fn write_object_buffered<P>(
+        &self,
+        payload: P,
+        req: WriteObjectRequest,
+        _options: RequestOptions,
+    ) -> gcs::Result<Object>
+    where
+        P: StreamingSource + Send + Sync + 'static,
+    {
+        let request = WriteRequest::new(&req, collect(payload).await?);
+        self.write(request)
+    }
+
+    async fn write_object_unbuffered<P>(
+        &self,
+        payload: P,
+        req: WriteObjectRequest,
+        _options: RequestOptions,
+    ) -> gcs::Result<Object>
+    where
+        P: StreamingSource + Seek + Send + Sync + 'static,
+    {
+        let request = WriteRequest::new(&req, collect(payload).await?);
+        self.write(request)
+    }
+}
+
+impl Replay {
+    fn write(&self, request: WriteRequest) -> gcs::Result<Object> {
+        match self.take(|i| matches!(i, Interaction::Write { request: r, .. } if *r == request)) {
+            Some(Interaction::Write { response, .. }) => {
+                response.map(Into::into).map_err(Into::into)
+            }
+            _ => Err(unrecorded(&request)),
+        }
+    }
+}
+
+fn unrecorded<R: std::fmt::Debug>(request: &R) -> gcs::Error {
+    gcs::Error::service(
+        Status::default()
+            .set_code(Code::Unimplemented)
+            .set_message(format!("no recorded response for {request:?}")),
+    )
+}
+
+// フィクスチャーファイルの形式
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Fixture {
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Interaction {
+    Read {
+        request: ReadRequest,
+        response: Result<ReadResponse, RecordedError>,
+    },
+    Write {
+        request: WriteRequest,
+        response: Result<RecordedObject, RecordedError>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ReadRequest {
+    bucket: String,
+    object: String,
+    #[serde(default)]
+    generation: i64,
+    #[serde(default)]
+    read_offset: i64,
+    #[serde(default)]
+    read_limit: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    if_generation_match: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    if_generation_not_match: Option<i64>,
+}
+
+impl From<&ReadObjectRequest> for ReadRequest {
+    fn from(req: &ReadObjectRequest) -> Self {
+        Self {
+            bucket: req.bucket.clone(),
+            object: req.object.clone(),
+            generation: req.generation,
+            read_offset: req.read_offset,
+            read_limit: req.read_limit,
+            if_generation_match: req.if_generation_match,
+            if_generation_not_match: req.if_generation_not_match,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ReadResponse {
+    generation: i64,
+    size: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    crc32c: Option<u32>,
+    #[serde(default, with = "base64_bytes")]
+    md5_hash: Bytes,
+    #[serde(with = "base64_bytes")]
+    contents: Bytes,
+}
+
+impl ReadResponse {
+    fn new(highlights: &ObjectHighlights, contents: Bytes) -> Self {
+        let checksums = highlights.checksums.clone().unwrap_or_default();
+        Self {
+            generation: highlights.generation,
+            size: highlights.size,
+            crc32c: checksums.crc32c,
+            md5_hash: checksums.md5_hash,
+            contents,
+        }
+    }
+
+    fn into_response(self) -> ReadObjectResponse {
+        let mut highlights = ObjectHighlights::default();
+        highlights.generation = self.generation;
+        highlights.size = self.size;
+        let mut checksums = ObjectChecksums::new().set_md5_hash(self.md5_hash);
+        checksums.crc32c = self.crc32c;
+        highlights.checksums = Some(checksums);
+        ReadObjectResponse::from_source(highlights, self.contents)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct WriteRequest {
+    bucket: String,
+    name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    content_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    if_generation_match: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    if_generation_not_match: Option<i64>,
+    #[serde(with = "base64_bytes")]
+    contents: Bytes,
+}
+
+impl WriteRequest {
+    fn new(req: &WriteObjectRequest, contents: Bytes) -> Self {
+        let resource = req.spec.resource.clone().unwrap_or_default();
+        Self {
+            bucket: resource.bucket,
+            name: resource.name,
+            content_type: resource.content_type,
+            if_generation_match: req.spec.if_generation_match,
+            if_generation_not_match: req.spec.if_generation_not_match,
+            contents,
+        }
+    }
+}
+
+// 書き込みのレスポンスのうち、記録するオブジェクトのメタデータ
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct RecordedObject {
+    bucket: String,
+    name: String,
+    generation: i64,
+    metageneration: i64,
+    size: i64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    content_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    crc32c: Option<u32>,
+    #[serde(default, with = "base64_bytes")]
+    md5_hash: Bytes,
+}
+
+impl From<&Object> for RecordedObject {
+    fn from(object: &Object) -> Self {
+        let checksums = object.checksums.clone().unwrap_or_default();
+        Self {
+            bucket: object.bucket.clone(),
+            name: object.name.clone(),
+            generation: object.generation,
+            metageneration: object.metageneration,
+            size: object.size,
+            content_type: object.content_type.clone(),
+            crc32c: checksums.crc32c,
+            md5_hash: checksums.md5_hash,
+        }
+    }
+}
+
+impl From<RecordedObject> for Object {
+    fn from(object: RecordedObject) -> Self {
+        let mut checksums = ObjectChecksums::new().set_md5_hash(object.md5_hash);
+        checksums.crc32c = object.crc32c;
+        Object::new()
+            .set_bucket(object.bucket)
+            .set_name(object.name)
+            .set_generation(object.generation)
+            .set_metageneration(object.metageneration)
+            .set_size(object.size)
+            .set_content_type(object.content_type)
+            .set_checksums(checksums)
+    }
+}
+
+// エラーのステータスコードとメッセージ
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct RecordedError {
+    code: String,
+    message: String,
+}
+
+impl From<&gcs::Error> for RecordedError {
+    fn from(error: &gcs::Error) -> Self {
+        // ステータスがない場合は、HTTPのステータスコードから推定
+        let (code, message) = match (error.status(), error.http_status_code()) {
+            (Some(status), _) => (status.code, status.message.clone()),
+            (None, Some(404)) => (Code::NotFound, error.to_string()),
+            (None, Some(412)) => (Code::FailedPrecondition, error.to_string()),
+            (None, Some(429)) => (Code::ResourceExhausted, error.to_string()),
+            (None, Some(503)) => (Code::Unavailable, error.to_string()),
+            (None, _) => (Code::Unknown, error.to_string()),
+        };
+        Self {
+            code: code_name(code).to_string(),
+            message,
+        }
+    }
+}
+
+impl From<RecordedError> for gcs::Error {
+    fn from(error: RecordedError) -> Self {
+        gcs::Error::service(
+            Status::default()
+                .set_code(parse_code(&error.code))
+                .set_message(error.message),
+        )
+    }
+}
+
+const CODES: [(Code, &str); 17] = [
+    (Code::Ok, "OK"),
+    (Code::Cancelled, "CANCELLED"),
+    (Code::Unknown, "UNKNOWN"),
+    (Code::InvalidArgument, "INVALID_ARGUMENT"),
+    (Code::DeadlineExceeded, "DEADLINE_EXCEEDED"),
+    (Code::NotFound, "NOT_FOUND"),
+    (Code::AlreadyExists, "ALREADY_EXISTS"),
+    (Code::PermissionDenied, "PERMISSION_DENIED"),
+    (Code::ResourceExhausted, "RESOURCE_EXHAUSTED"),
+    (Code::FailedPrecondition, "FAILED_PRECONDITION"),
+    (Code::Aborted, "ABORTED"),
+    (Code::OutOfRange, "OUT_OF_RANGE"),
+    (Code::Unimplemented, "UNIMPLEMENTED"),
+    (Code::Internal, "INTERNAL"),
+    (Code::Unavailable, "UNAVAILABLE"),
+    (Code::DataLoss, "DATA_LOSS"),
+    (Code::Unauthenticated, "UNAUTHENTICATED"),
+];
+
+fn code_name(code: Code) -> &'static str {
+    CODES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map_or("UNKNOWN", |(_, name)| name)
+}
+
+fn parse_code(name: &str) -> Code {
+    CODES
+        .iter()
+        .find(|(_, n)| *n == name)
+        .map_or(Code::Unknown, |(code, _)| *code)
+}
+
+// `Bytes`をBase64の文字列としてシリアライズ
+mod base64_bytes {
+    use base64::Engine as _;
+    use base64::engine::general_purpose::STANDARD;
+    use bytes::Bytes;
+    use serde::{Deserialize as _, Deserializer, Serializer};
+
+    pub fn serialize<S>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Bytes, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD
+            .decode(encoded)
+            .map(Bytes::from)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Recorder, Replay, forward};
+    use crate::FakeStorage;
+    use gcs::client::Storage;
+    use gcs::model::{CommonObjectRequestParams, Object, WriteObjectSpec};
+    use gcs::model_ext::ReadRange;
+    use google_cloud_gax::error::rpc::Code;
+    use google_cloud_storage as gcs;
+
+    const BUCKET: &str = "projects/_/buckets/my-bucket";
+
+    // 同じ手順をスタブに対して実行して、結果を返す
+    async fn exercise<T>(client: &Storage<T>) -> anyhow::Result<(Vec<u8>, i64, Option<Code>)>
+    where
+        T: gcs::stub::Storage + 'static,
+    {
+        let mut reader = client
+            .read_object(BUCKET, "my-object")
+            .set_read_range(ReadRange::segment(2, 5))
+            .send()
+            .await?;
+        let mut contents = Vec::new();
+        while let Some(b) = reader.next().await.transpose()? {
+            contents.extend_from_slice(&b);
+        }
+        let object = client
+            .write_object(BUCKET, "new-object", "payload")
+            .set_if_generation_match(0)
+            .send_unbuffered()
+            .await?;
+        let err = client
+            .read_object(BUCKET, "missing")
+            .send()
+            .await
+            .expect_err("the object does not exist");
+        Ok((contents, object.generation, err.status().map(|s| s.code)))
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        fake.insert(BUCKET, "my-object", "0123456789");
+        let recorder = Recorder::new(fake);
+        let recorded = exercise(&Storage::from_stub(recorder.clone())).await?;
+        assert_eq!(recorded.0, b"23456");
+        assert_eq!(recorded.2, Some(Code::NotFound));
+
+        let path = std::env::temp_dir().join(format!("fixture-{}.json", uuid::Uuid::new_v4()));
+        recorder.save(&path).await?;
+        let replay = Replay::load(&path).await?;
+        assert_eq!(replay.remaining(), 3);
+        let replayed = exercise(&Storage::from_stub(replay.clone())).await?;
+        assert_eq!(replayed, recorded);
+        assert_eq!(replay.remaining(), 0);
+
+        // 記録されていないリクエストはエラーになる
+        let err = Storage::from_stub(replay)
+            .read_object(BUCKET, "my-object")
+            .send()
+            .await
+            .expect_err("the request was not recorded");
+        assert_eq!(err.status().map(|s| s.code), Some(Code::Unimplemented));
+
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forward() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+        let resource = Object::new()
+            .set_bucket(BUCKET)
+            .set_name("my-object")
+            .set_content_type("text/plain")
+            .set_cache_control("no-cache")
+            .set_content_language("ja")
+            .set_storage_class("NEARLINE")
+            .set_metadata([("owner", "team-a")]);
+        let spec = WriteObjectSpec::new()
+            .set_resource(resource)
+            .set_if_generation_match(0);
+        forward(
+            client.write_object(BUCKET, "my-object", "payload"),
+            spec,
+            None,
+        )?
+        .send_buffered()
+        .await?;
+        let object = fake
+            .object(BUCKET, "my-object")
+            .expect("the object was written");
+        assert_eq!(object.content_type, "text/plain");
+        assert_eq!(object.cache_control, "no-cache");
+        assert_eq!(object.content_language, "ja");
+        assert_eq!(object.storage_class, "NEARLINE");
+        assert_eq!(
+            object.metadata.get("owner").map(String::as_str),
+            Some("team-a")
+        );
+
+        // 転送できない指定はエラーになる
+        let params = Some(CommonObjectRequestParams::new());
+        let builder = client.write_object(BUCKET, "my-object", "payload");
+        assert!(forward(builder, WriteObjectSpec::new(), params).is_err());
+        let spec = WriteObjectSpec::new().set_appendable(true);
+        let builder = client.write_object(BUCKET, "my-object", "payload");
+        assert!(forward(builder, spec, None).is_err());
+        Ok(())
+    }
+}
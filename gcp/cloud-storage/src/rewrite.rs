@@ -0,0 +1,442 @@
+//! # 書き換えトークンを記録する`rewrite_object`のドライバー
+//!
+//! 巨大なオブジェクトの`rewrite_object`は、1回のリクエストで完了せずに書き換えトークンを返す。
+//! `RewriteDriver`は書き換えトークンを指定してリクエストを繰り返し、完了したオブジェクトを返す。
+//!
+//! チェックポイントストアを設定した場合は、リクエストごとに最新の書き換えトークンを保存する。
+//! プロセスが途中で終了しても、同じキーで再実行すると保存したトークンから再開する。
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+
+use google_cloud_gax::error::rpc::Code;
+use google_cloud_storage as gcs;
+use google_cloud_storage::builder::storage_control::RewriteObject;
+use google_cloud_storage::model::Object;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// 書き換えの進捗
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RewriteProgress {
+    /// 書き換えたバイト数
+    pub total_bytes_rewritten: i64,
+    /// オブジェクトのサイズ
+    pub object_size: i64,
+    /// 発行したリクエストの数
+    pub requests: usize,
+    /// 保存した書き換えトークンから再開した場合は`true`
+    pub resumed: bool,
+    /// 書き換えが完了した場合は`true`
+    pub done: bool,
+}
+
+/// 書き換えトークンを保存するストア
+///
+/// 書き換えトークンは、同じ書き換え元と書き換え先、同じオプションのリクエストでのみ有効である。
+/// キーには、これらを区別できる値を使用する。
+pub trait CheckpointStore: Send + Sync {
+    /// 保存した書き換えトークンを返す。
+    fn load(&self, key: &str) -> impl Future<Output = io::Result<Option<String>>> + Send;
+
+    /// 書き換えトークンを保存する。
+    fn save(&self, key: &str, rewrite_token: &str) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// 書き換えトークンを削除する。
+    fn remove(&self, key: &str) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// 書き換えトークンを保存しないストア
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoCheckpoint;
+
+impl CheckpointStore for NoCheckpoint {
+    async fn load(&self, _key: &str) -> io::Result<Option<String>> {
+        Ok(None)
+    }
+
+    async fn save(&self, _key: &str, _rewrite_token: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn remove(&self, _key: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// ディレクトリに書き換えトークンを保存するストア
+///
+/// キーごとに1つのファイルを作成する。
+/// ファイル名はキーのMD5ハッシュの16進数で、キーが長くてもファイル名の長さの上限を超えない。
+/// ファイルにはキーも保存して、読み込む時に一致することを確認する。
+#[derive(Clone, Debug)]
+pub struct FileCheckpointStore {
+    directory: PathBuf,
+}
+
+impl FileCheckpointStore {
+    /// ディレクトリが存在しない場合は、最初に保存する時に作成する。
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory
+            .join(format!("{:x}.rewrite", md5::compute(key)))
+    }
+}
+
+// `FileCheckpointStore`のファイルの内容
+#[derive(Deserialize, Serialize)]
+struct Checkpoint {
+    key: String,
+    rewrite_token: String,
+}
+
+impl CheckpointStore for FileCheckpointStore {
+    async fn load(&self, key: &str) -> io::Result<Option<String>> {
+        let contents = match tokio::fs::read(self.path(key)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let checkpoint: Checkpoint = serde_json::from_slice(&contents)?;
+        Ok(Some(checkpoint.rewrite_token).filter(|t| checkpoint.key == key && !t.is_empty()))
+    }
+
+    async fn save(&self, key: &str, rewrite_token: &str) -> io::Result<()> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+        // 書き込みの途中で終了しても壊れないように、一時ファイルに書き込んでから置き換える
+        let path = self.path(key);
+        let temporary = path.with_extension("rewrite.tmp");
+        let checkpoint = Checkpoint {
+            key: key.to_string(),
+            rewrite_token: rewrite_token.to_string(),
+        };
+        tokio::fs::write(&temporary, serde_json::to_vec(&checkpoint)?).await?;
+        tokio::fs::rename(&temporary, &path).await
+    }
+
+    async fn remove(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `rewrite_object`を完了するまで繰り返す。
+///
+/// ```ignore
+/// let builder = control
+///     .rewrite_object()
+///     .set_source_bucket(&bucket.name)
+///     .set_source_object("source")
+///     .set_destination_bucket(&bucket.name)
+///     .set_destination_name("destination");
+/// let object = RewriteDriver::new(builder)
+///     .with_checkpoint(FileCheckpointStore::new(".checkpoints"), "source->destination")
+///     .run()
+///     .await?;
+/// ```
+pub struct RewriteDriver<C = NoCheckpoint> {
+    builder: RewriteObject,
+    store: C,
+    key: String,
+    progress: watch::Sender<RewriteProgress>,
+}
+
+impl RewriteDriver {
+    pub fn new(builder: RewriteObject) -> Self {
+        Self {
+            builder,
+            store: NoCheckpoint,
+            key: String::new(),
+            progress: watch::Sender::new(RewriteProgress::default()),
+        }
+    }
+}
+
+impl<C> RewriteDriver<C>
+where
+    C: CheckpointStore,
+{
+    /// 書き換えトークンを`store`に`key`で保存する。
+    pub fn with_checkpoint<D>(self, store: D, key: impl Into<String>) -> RewriteDriver<D>
+    where
+        D: CheckpointStore,
+    {
+        RewriteDriver {
+            builder: self.builder,
+            store,
+            key: key.into(),
+            progress: self.progress,
+        }
+    }
+
+    /// 書き換えの進捗を受け取るレシーバーを返す。
+    ///
+    /// 進捗はレスポンスを受け取るたびに更新される。
+    pub fn subscribe(&self) -> watch::Receiver<RewriteProgress> {
+        self.progress.subscribe()
+    }
+
+    /// 書き換えを完了して、書き換え先のオブジェクトを返す。
+    ///
+    /// 保存した書き換えトークンが無効になっていた場合は、最初から書き換える。
+    /// 完了した後は、保存した書き換えトークンを削除する。
+    pub async fn run(self) -> anyhow::Result<Object> {
+        let mut builder = self.builder.clone();
+        let mut resumed = false;
+        if let Some(token) = self.store.load(&self.key).await? {
+            builder = builder.set_rewrite_token(token);
+            resumed = true;
+            self.progress.send_modify(|p| p.resumed = true);
+        }
+
+        loop {
+            let response = match builder.clone().send().await {
+                Ok(response) => response,
+                Err(e) if resumed && is_invalid_token(&e) => {
+                    // 書き換えトークンの有効期限が切れたため、最初からやり直す
+                    self.store.remove(&self.key).await?;
+                    builder = self.builder.clone();
+                    resumed = false;
+                    self.progress.send_modify(|p| p.resumed = false);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            resumed = false;
+            self.progress.send_modify(|p| {
+                p.total_bytes_rewritten = response.total_bytes_rewritten;
+                p.object_size = response.object_size;
+                p.requests += 1;
+                p.done = response.done;
+            });
+
+            if response.done {
+                self.store.remove(&self.key).await?;
+                return Ok(response
+                    .resource
+                    .expect("A `done` response must have an object."));
+            }
+            self.store.save(&self.key, &response.rewrite_token).await?;
+            builder = builder.set_rewrite_token(response.rewrite_token);
+        }
+    }
+}
+
+// 保存した書き換えトークンが使用できないエラー
+fn is_invalid_token(error: &gcs::Error) -> bool {
+    error
+        .status()
+        .is_some_and(|s| matches!(s.code, Code::InvalidArgument | Code::NotFound))
+}
+
+// 書き換え先のオブジェクトを作成
+//
+// 書き換え先を指定すると未指定のメタデータは失われるため、元のオブジェクトから出力専用の
+// フィールドを除き、ストレージクラスのみを変更する
+pub(crate) fn destination(object: &Object, storage_class: &str) -> Object {
+    let mut destination = object.clone();
+    destination.name.clear();
+    destination.bucket.clear();
+    destination.etag.clear();
+    destination.generation = 0;
+    destination.restore_token = None;
+    destination.metageneration = 0;
+    destination.size = 0;
+    destination.component_count = 0;
+    destination.checksums = None;
+    destination.kms_key.clear();
+    destination.customer_encryption = None;
+    destination.owner = None;
+    destination.create_time = None;
+    destination.update_time = None;
+    destination.delete_time = None;
+    destination.finalize_time = None;
+    destination.update_storage_class_time = None;
+    destination.retention_expire_time = None;
+    destination.soft_delete_time = None;
+    destination.hard_delete_time = None;
+    destination.set_storage_class(storage_class)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CheckpointStore, FileCheckpointStore, RewriteDriver};
+    use gcs::client::StorageControl;
+    use gcs::model::{Object, RewriteObjectRequest, RewriteResponse};
+    use google_cloud_gax as gax;
+    use google_cloud_storage as gcs;
+
+    mockall::mock! {
+        #[derive(Debug)]
+        StorageControl {}
+
+        impl gcs::stub::StorageControl for StorageControl {
+            async fn rewrite_object(&self, req: RewriteObjectRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<RewriteResponse>>;
+        }
+    }
+
+    const SIZE: i64 = 3 * 1024 * 1024;
+
+    // 1回のリクエストで1MiBずつ書き換える
+    fn rewrite(
+        req: RewriteObjectRequest,
+        _options: gax::options::RequestOptions,
+    ) -> gax::Result<gax::response::Response<RewriteResponse>> {
+        let step = match req.rewrite_token.as_str() {
+            "" => 1,
+            "token-1" => 2,
+            "token-2" => 3,
+            token => {
+                use gax::error::rpc::{Code, Status};
+                let status = Status::default()
+                    .set_code(Code::InvalidArgument)
+                    .set_message(format!("invalid rewrite token {token}"));
+                return Err(gax::error::Error::service(status));
+            }
+        };
+        let response = RewriteResponse::new()
+            .set_total_bytes_rewritten(step * 1024 * 1024)
+            .set_object_size(SIZE);
+        let response = if step == 3 {
+            response.set_done(true).set_resource(
+                Object::new()
+                    .set_bucket(&req.destination_bucket)
+                    .set_name(&req.destination_name)
+                    .set_size(SIZE),
+            )
+        } else {
+            response.set_rewrite_token(format!("token-{step}"))
+        };
+        Ok(gax::response::Response::from(response))
+    }
+
+    fn builder(control: &StorageControl) -> gcs::builder::storage_control::RewriteObject {
+        control
+            .rewrite_object()
+            .set_source_bucket("projects/_/buckets/my-bucket")
+            .set_source_object("source")
+            .set_destination_bucket("projects/_/buckets/my-bucket")
+            .set_destination_name("destination")
+    }
+
+    fn store() -> FileCheckpointStore {
+        FileCheckpointStore::new(
+            std::env::temp_dir().join(format!("rewrite-{}", uuid::Uuid::new_v4())),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_rewrite() -> anyhow::Result<()> {
+        let mut mock = MockStorageControl::new();
+        mock.expect_rewrite_object().times(3).returning(rewrite);
+        let control = StorageControl::from_stub(mock);
+
+        let store = store();
+        let driver = RewriteDriver::new(builder(&control)).with_checkpoint(store.clone(), "key");
+        let progress = driver.subscribe();
+        let object = driver.run().await?;
+        assert_eq!(object.name, "destination");
+        assert_eq!(object.size, SIZE);
+
+        let progress = progress.borrow().clone();
+        assert_eq!(progress.total_bytes_rewritten, SIZE);
+        assert_eq!(progress.object_size, SIZE);
+        assert_eq!(progress.requests, 3);
+        assert!(progress.done);
+        assert!(!progress.resumed);
+
+        // 完了した後は、書き換えトークンを削除する
+        assert_eq!(store.load("key").await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_long_key() -> anyhow::Result<()> {
+        // ファイル名の長さは、キーの長さによらない
+        let store = store();
+        let key = format!("projects/_/buckets/my-bucket/{}", "x".repeat(1024));
+        store.save(&key, "token-1").await?;
+        assert_eq!(store.load(&key).await?.as_deref(), Some("token-1"));
+        let name = store.path(&key);
+        assert!(name.file_name().is_some_and(|n| n.len() < 255), "{name:?}");
+
+        // 保存したキーと一致しない場合は、書き換えトークンを返さない
+        tokio::fs::rename(&name, store.path("other")).await?;
+        assert_eq!(store.load("other").await?, None);
+        store.remove("other").await?;
+        assert_eq!(store.load(&key).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume() -> anyhow::Result<()> {
+        let store = store();
+
+        // 2回目のリクエストで失敗する（再試行されないエラーを返す）
+        let mut mock = MockStorageControl::new();
+        let mut seq = mockall::Sequence::new();
+        mock.expect_rewrite_object()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(rewrite);
+        mock.expect_rewrite_object()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| {
+                use gax::error::rpc::{Code, Status};
+                let status = Status::default()
+                    .set_code(Code::PermissionDenied)
+                    .set_message("the credentials were revoked");
+                Err(gax::error::Error::service(status))
+            });
+        let control = StorageControl::from_stub(mock);
+        let err = RewriteDriver::new(builder(&control))
+            .with_checkpoint(store.clone(), "key")
+            .run()
+            .await
+            .expect_err("the second request fails");
+        assert!(err.downcast_ref::<gcs::Error>().is_some(), "{err:?}");
+        assert_eq!(store.load("key").await?.as_deref(), Some("token-1"));
+
+        // 保存した書き換えトークンから再開する
+        let mut mock = MockStorageControl::new();
+        mock.expect_rewrite_object()
+            .withf(|r, _| !r.rewrite_token.is_empty())
+            .times(2)
+            .returning(rewrite);
+        let control = StorageControl::from_stub(mock);
+        let driver = RewriteDriver::new(builder(&control)).with_checkpoint(store.clone(), "key");
+        let progress = driver.subscribe();
+        driver.run().await?;
+        assert_eq!(progress.borrow().requests, 2);
+        assert!(progress.borrow().resumed);
+        assert_eq!(store.load("key").await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_token() -> anyhow::Result<()> {
+        let store = store();
+        store.save("key", "expired").await?;
+
+        // 無効な書き換えトークンを削除して、最初から書き換える
+        let mut mock = MockStorageControl::new();
+        mock.expect_rewrite_object().times(4).returning(rewrite);
+        let control = StorageControl::from_stub(mock);
+        let driver = RewriteDriver::new(builder(&control)).with_checkpoint(store.clone(), "key");
+        let progress = driver.subscribe();
+        let object = driver.run().await?;
+        assert_eq!(object.name, "destination");
+        assert_eq!(progress.borrow().requests, 3);
+        assert!(!progress.borrow().resumed);
+        assert_eq!(store.load("key").await?, None);
+        Ok(())
+    }
+}
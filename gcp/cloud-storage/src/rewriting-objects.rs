@@ -1,27 +1,21 @@
-use gcs::builder::storage_control::RewriteObject;
 use gcs::client::StorageControl;
 use gcs::model::Object;
 use gcs::retry_policy::RetryableErrors;
 use google_cloud_gax::retry_policy::RetryPolicyExt as _;
 use google_cloud_storage as gcs;
 
+use cloud_storage::{FileCheckpointStore, RewriteDriver, TempBucket};
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let project_id = std::env::args().nth(1).unwrap();
 
-    // バケットを作成
-    let bucket_id = format!("my-bucket-{}", uuid::Uuid::new_v4());
+    // 一時的なバケットを作成
     let control = StorageControl::builder().build().await?;
-    let bucket = control
-        .create_bucket()
-        .set_parent("projects/_")
-        .set_bucket_id(&bucket_id)
-        .set_bucket(gcs::model::Bucket::new().set_project(format!("projects/{project_id}")))
-        .send()
-        .await?;
-    println!("bucket successfully created {bucket:?}");
+    let bucket = TempBucket::create(&control, &project_id).await?;
+    println!("bucket successfully created {:?}", bucket.bucket());
 
-    let source_object = upload(&bucket.name).await?;
+    let source_object = upload(bucket.name()).await?;
 
     let control = StorageControl::builder()
         .with_retry_policy(RetryableErrors.with_attempt_limit(5))
@@ -30,9 +24,9 @@ async fn main() -> anyhow::Result<()> {
 
     let mut builder = control
         .rewrite_object()
-        .set_source_bucket(&bucket.name)
+        .set_source_bucket(bucket.name())
         .set_source_object(&source_object.name)
-        .set_destination_bucket(&bucket.name)
+        .set_destination_bucket(bucket.name())
         .set_destination_name("rewrite-object-clone");
 
     // オプションで、リクエストごとの最大書き込みバイト数を制限
@@ -41,53 +35,32 @@ async fn main() -> anyhow::Result<()> {
     // オプションで、バイトコピーをGCSに強制するためにストレージクラスを変更
     builder = builder.set_destination(Object::new().set_storage_class("NEARLINE"));
 
-    let dest_object = loop {
-        let progress = make_one_request(builder.clone()).await?;
-        match progress {
-            RewriteProgress::Incomplete(rewrite_token) => {
-                builder = builder.set_rewrite_token(rewrite_token);
-            }
-            RewriteProgress::Done(object) => break object,
-        };
-    };
+    // 書き換えトークンをファイルに保存して、中断しても再開できるようにする
+    let store = FileCheckpointStore::new(std::env::temp_dir().join("rewriting-objects"));
+    let key = format!("{}/{}", bucket.name(), source_object.name);
+    let driver = RewriteDriver::new(builder).with_checkpoint(store, key);
+
+    let mut progress = driver.subscribe();
+    let printer = tokio::spawn(async move {
+        while progress.changed().await.is_ok() {
+            let p = progress.borrow_and_update().clone();
+            let label = if p.done { "DONE" } else { "PROGRESS" };
+            println!(
+                "{label}: total_bytes_rewritten={}; object_size={}",
+                p.total_bytes_rewritten, p.object_size
+            );
+        }
+    });
+    let dest_object = driver.run().await?;
+    printer.await?;
     println!("dest_object={dest_object:?}");
 
-    cleanup(
-        control,
-        &bucket.name,
-        &source_object.name,
-        &dest_object.name,
-    )
-    .await;
+    // 作成したオブジェクトをすべて削除してから、バケットを削除
+    bucket.delete().await?;
 
     Ok(())
 }
 
-enum RewriteProgress {
-    // これは書き換えトークンを保持
-    Incomplete(String),
-    Done(Box<Object>),
-}
-
-async fn make_one_request(builder: RewriteObject) -> gcs::Result<RewriteProgress> {
-    let resp = builder.send().await?;
-    if resp.done {
-        println!(
-            "DONE: total_bytes_rewritten={}; object_size={}",
-            resp.total_bytes_rewritten, resp.object_size
-        );
-        return Ok(RewriteProgress::Done(Box::new(
-            resp.resource
-                .expect("A `done` response must have an object."),
-        )));
-    }
-    println!(
-        "PROGRESS: total_bytes_rewritten={}; object_size={}",
-        resp.total_bytes_rewritten, resp.object_size
-    );
-    Ok(RewriteProgress::Incomplete(resp.rewrite_token))
-}
-
 // 書き換えするためにオブジェクトをアップロード
 async fn upload(bucket_name: &str) -> anyhow::Result<Object> {
     let storage = gcs::client::Storage::builder().build().await?;
@@ -99,20 +72,3 @@ async fn upload(bucket_name: &str) -> anyhow::Result<Object> {
         .await?;
     Ok(object)
 }
-
-// この例で作成したリソースをクリーンアップ
-async fn cleanup(control: StorageControl, bucket_name: &str, o1: &str, o2: &str) {
-    let _ = control
-        .delete_object()
-        .set_bucket(bucket_name)
-        .set_object(o1)
-        .send()
-        .await;
-    let _ = control
-        .delete_object()
-        .set_bucket(bucket_name)
-        .set_object(o2)
-        .send()
-        .await;
-    let _ = control.delete_bucket().set_name(bucket_name).send().await;
-}
@@ -0,0 +1,645 @@
+//! # オブジェクトをストリームで走査するユーティリティ
+//!
+//! `read_object`のレスポンスをチャンクごとに読み込みながら、行、CSVのレコード、NDJSONのレコードを取り出す。
+//! オブジェクト全体をメモリに読み込まないため、巨大なオブジェクトにも使用できる。
+//!
+//! ```ignore
+//! let scanner = ObjectScanner::new(&client, "projects/_/buckets/my-bucket", "logs.txt");
+//! let mut lines = scanner.grep("ERROR").await?;
+//! while let Some(line) = lines.next().await.transpose()? {
+//!     println!("{}: {}", line.number, String::from_utf8_lossy(&line.text));
+//! }
+//! ```
+use std::error::Error;
+use std::fmt::Display;
+use std::marker::PhantomData;
+
+use bytes::{Bytes, BytesMut};
+use futures::Stream;
+use google_cloud_storage as gcs;
+use google_cloud_storage::client::Storage;
+use google_cloud_storage::model_ext::ReadRange;
+use google_cloud_storage::read_object::ReadObjectResponse;
+use serde::de::DeserializeOwned;
+
+/// 走査するオブジェクト
+///
+/// 世代を指定しない場合は、最新の世代を読み込む。
+pub struct ObjectScanner<'a, T>
+where
+    T: gcs::stub::Storage + 'static,
+{
+    client: &'a Storage<T>,
+    bucket: String,
+    object: String,
+    generation: Option<i64>,
+}
+
+impl<'a, T> ObjectScanner<'a, T>
+where
+    T: gcs::stub::Storage + 'static,
+{
+    /// `bucket`は`projects/_/buckets/{bucket_name}`形式のバケット名を指定する。
+    pub fn new(
+        client: &'a Storage<T>,
+        bucket: impl Into<String>,
+        object: impl Into<String>,
+    ) -> Self {
+        Self {
+            client,
+            bucket: bucket.into(),
+            object: object.into(),
+            generation: None,
+        }
+    }
+
+    /// 読み込むオブジェクトの世代を設定する。
+    pub fn with_generation(mut self, generation: i64) -> Self {
+        self.generation = Some(generation);
+        self
+    }
+
+    /// オブジェクトの`range`の範囲を読み込むレスポンスを返す。
+    pub async fn read(&self, range: ReadRange) -> gcs::Result<ReadObjectResponse> {
+        let mut builder = self
+            .client
+            .read_object(&self.bucket, &self.object)
+            .set_read_range(range);
+        if let Some(generation) = self.generation {
+            builder = builder.set_generation(generation);
+        }
+        builder.send().await
+    }
+
+    /// オブジェクトの行を返すイテレーターを返す。
+    pub async fn lines(&self) -> gcs::Result<Lines> {
+        Ok(Lines::new(self.read(ReadRange::all()).await?))
+    }
+
+    /// オブジェクトの行数を返す。
+    ///
+    /// 最後の行が改行で終わっていない場合も、1行として数える。
+    pub async fn count_lines(&self) -> gcs::Result<usize> {
+        let mut lines = self.lines().await?;
+        let mut count = 0;
+        while lines.next().await.transpose()?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// `pattern`を含む行を返すイテレーターを返す。
+    pub async fn grep(&self, pattern: impl AsRef<[u8]>) -> gcs::Result<Grep> {
+        Ok(self.lines().await?.grep(pattern))
+    }
+
+    /// CSVのレコードを返すイテレーターを返す。
+    pub async fn csv(&self) -> gcs::Result<CsvRecords> {
+        Ok(CsvRecords::new(self.lines().await?))
+    }
+
+    /// NDJSONの各行を`R`にデシリアライズするイテレーターを返す。
+    pub async fn ndjson<R: DeserializeOwned>(&self) -> gcs::Result<NdjsonRecords<R>> {
+        Ok(NdjsonRecords::new(self.lines().await?))
+    }
+
+    /// オブジェクトの先頭の`n`バイトを返す。
+    ///
+    /// `n`が0の場合は、リクエストを送信せずに空のデータを返す。
+    pub async fn head(&self, n: u64) -> gcs::Result<Bytes> {
+        // `ReadRange`の長さ0はオブジェクト全体を意味するため、ここで処理する
+        if n == 0 {
+            return Ok(Bytes::new());
+        }
+        collect(self.read(ReadRange::segment(0, n)).await?).await
+    }
+
+    /// オブジェクトの末尾の`n`バイトを返す。
+    ///
+    /// `n`が0の場合は、リクエストを送信せずに空のデータを返す。
+    pub async fn tail(&self, n: u64) -> gcs::Result<Bytes> {
+        if n == 0 {
+            return Ok(Bytes::new());
+        }
+        collect(self.read(ReadRange::tail(n)).await?).await
+    }
+}
+
+/// 走査のエラー
+#[derive(Debug)]
+pub enum ScanError {
+    /// オブジェクトの読み込みに失敗した
+    Storage(gcs::Error),
+    /// CSVのレコードを解析できない
+    Csv { line: usize, message: String },
+    /// NDJSONのレコードを解析できない
+    ///
+    /// `offset`はオブジェクトの先頭から、エラーを検出した位置までのバイト数を表す。
+    Json {
+        line: usize,
+        offset: u64,
+        source: serde_json::Error,
+    },
+}
+
+impl Error for ScanError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Storage(e) => Some(e),
+            Self::Csv { .. } => None,
+            Self::Json { source, .. } => Some(source),
+        }
+    }
+}
+
+impl Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Storage(e) => write!(f, "cannot read the object: {e}"),
+            Self::Csv { line, message } => {
+                write!(f, "invalid CSV record at line {line}: {message}")
+            }
+            Self::Json {
+                line,
+                offset,
+                source,
+            } => write!(
+                f,
+                "invalid JSON record at line {line} (byte offset {offset}): {source}"
+            ),
+        }
+    }
+}
+
+impl From<gcs::Error> for ScanError {
+    fn from(e: gcs::Error) -> Self {
+        Self::Storage(e)
+    }
+}
+
+/// オブジェクトの1行
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    /// 1から数えた行番号
+    pub number: usize,
+    /// オブジェクトの先頭から、行の先頭までのバイト数
+    pub offset: u64,
+    /// 行末の`\n`または`\r\n`を除いた行の内容
+    pub text: Bytes,
+}
+
+/// レスポンスを行に分割するイテレーター
+///
+/// チャンクの境界をまたぐ行は、次のチャンクを読み込んでから返す。
+pub struct Lines {
+    reader: ReadObjectResponse,
+    buffer: BytesMut,
+    // `buffer`のうち、改行がないことを確認したバイト数
+    scanned: usize,
+    number: usize,
+    offset: u64,
+    done: bool,
+}
+
+impl Lines {
+    pub fn new(reader: ReadObjectResponse) -> Self {
+        Self {
+            reader,
+            buffer: BytesMut::new(),
+            scanned: 0,
+            number: 0,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// 次の行を返す。
+    pub async fn next(&mut self) -> Option<gcs::Result<Line>> {
+        loop {
+            if let Some(i) = self.buffer[self.scanned..].iter().position(|b| *b == b'\n') {
+                let raw = self.buffer.split_to(self.scanned + i + 1).freeze();
+                self.scanned = 0;
+                return Some(Ok(self.line(raw)));
+            }
+            self.scanned = self.buffer.len();
+            if self.done {
+                if self.buffer.is_empty() {
+                    return None;
+                }
+                let raw = self.buffer.split().freeze();
+                self.scanned = 0;
+                return Some(Ok(self.line(raw)));
+            }
+            match self.reader.next().await {
+                Some(Ok(b)) => self.buffer.extend_from_slice(&b),
+                Some(Err(e)) => return Some(Err(e)),
+                None => self.done = true,
+            }
+        }
+    }
+
+    /// `pattern`を含む行のみを返すイテレーターに変換する。
+    pub fn grep(self, pattern: impl AsRef<[u8]>) -> Grep {
+        Grep {
+            lines: self,
+            pattern: pattern.as_ref().to_vec(),
+        }
+    }
+
+    fn line(&mut self, raw: Bytes) -> Line {
+        self.number += 1;
+        let offset = self.offset;
+        self.offset += raw.len() as u64;
+        let text = raw
+            .strip_suffix(b"\n")
+            .map(|t| t.strip_suffix(b"\r").unwrap_or(t))
+            .map_or(raw.clone(), |t| raw.slice_ref(t));
+        Line {
+            number: self.number,
+            offset,
+            text,
+        }
+    }
+}
+
+/// パターンを含む行のみを返すイテレーター
+pub struct Grep {
+    lines: Lines,
+    pattern: Vec<u8>,
+}
+
+impl Grep {
+    /// パターンを含む次の行を返す。
+    pub async fn next(&mut self) -> Option<gcs::Result<Line>> {
+        loop {
+            match self.lines.next().await? {
+                Ok(line) if contains(&line.text, &self.pattern) => return Some(Ok(line)),
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// CSVのレコード
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsvRecord {
+    /// レコードの最初の行番号
+    pub line: usize,
+    pub fields: Vec<String>,
+}
+
+/// CSVのレコードを返すイテレーター
+///
+/// 引用符で囲まれたフィールドの`,`、改行、`""`を扱える。空の行は無視する。
+pub struct CsvRecords {
+    lines: Lines,
+}
+
+impl CsvRecords {
+    pub fn new(lines: Lines) -> Self {
+        Self { lines }
+    }
+
+    /// 次のレコードを返す。
+    pub async fn next(&mut self) -> Option<Result<CsvRecord, ScanError>> {
+        let mut record = String::new();
+        let mut first = None;
+        loop {
+            let line = match self.lines.next().await {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => return Some(Err(e.into())),
+                // 引用符が閉じられないままオブジェクトが終わった
+                None => {
+                    return first.map(|line| {
+                        Err(ScanError::Csv {
+                            line,
+                            message: "unterminated quoted field".to_string(),
+                        })
+                    });
+                }
+            };
+            let text = match std::str::from_utf8(&line.text) {
+                Ok(text) => text,
+                Err(e) => {
+                    return Some(Err(ScanError::Csv {
+                        line: line.number,
+                        message: e.to_string(),
+                    }));
+                }
+            };
+            if first.is_none() && text.is_empty() {
+                continue;
+            }
+            let line_number = *first.get_or_insert(line.number);
+            if line_number != line.number {
+                record.push('\n');
+            }
+            record.push_str(text);
+            if let Some(fields) = split_csv(&record) {
+                return Some(Ok(CsvRecord {
+                    line: line_number,
+                    fields,
+                }));
+            }
+        }
+    }
+}
+
+/// NDJSONの各行を`R`にデシリアライズするイテレーター
+///
+/// 1行ずつデシリアライズするため、オブジェクト全体をメモリに読み込まない。空の行は無視する。
+pub struct NdjsonRecords<R = serde_json::Value> {
+    lines: Lines,
+    _record: PhantomData<fn() -> R>,
+}
+
+impl<R: DeserializeOwned> NdjsonRecords<R> {
+    pub fn new(lines: Lines) -> Self {
+        Self {
+            lines,
+            _record: PhantomData,
+        }
+    }
+
+    /// 次のレコードを返す。
+    pub async fn next(&mut self) -> Option<Result<R, ScanError>> {
+        loop {
+            let line = match self.lines.next().await? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if line.text.trim_ascii().is_empty() {
+                continue;
+            }
+            return Some(
+                serde_json::from_slice(&line.text).map_err(|source| ScanError::Json {
+                    line: line.number,
+                    // 列番号は1から数える
+                    offset: line.offset + source.column().saturating_sub(1) as u64,
+                    source,
+                }),
+            );
+        }
+    }
+
+    /// `Stream`に変換する。
+    pub fn into_stream(self) -> impl Stream<Item = Result<R, ScanError>> {
+        futures::stream::unfold(self, |mut records| async move {
+            records.next().await.map(|r| (r, records))
+        })
+    }
+}
+
+// レスポンスをすべて読み込み
+async fn collect(mut reader: ReadObjectResponse) -> gcs::Result<Bytes> {
+    let mut contents = BytesMut::new();
+    while let Some(b) = reader.next().await.transpose()? {
+        contents.extend_from_slice(&b);
+    }
+    Ok(contents.freeze())
+}
+
+fn contains(text: &[u8], pattern: &[u8]) -> bool {
+    pattern.is_empty() || text.windows(pattern.len()).any(|w| w == pattern)
+}
+
+// CSVのレコードをフィールドに分割
+//
+// 引用符が閉じられていない場合は、レコードが次の行に続くため`None`を返す。
+fn split_csv(record: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') => quoted = true,
+            (false, ',') => fields.push(std::mem::take(&mut field)),
+            (_, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    fields.push(field);
+    Some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CsvRecords, Lines, NdjsonRecords, ObjectScanner, ScanError, split_csv};
+    use crate::FakeStorage;
+    use gcs::model_ext::ObjectHighlights;
+    use gcs::read_object::ReadObjectResponse;
+    use gcs::streaming_source::StreamingSource;
+    use google_cloud_storage as gcs;
+
+    // 内容を`size`バイトずつのチャンクで返すソース
+    #[derive(Debug)]
+    struct ChunkedSource {
+        contents: bytes::Bytes,
+        size: usize,
+    }
+
+    impl StreamingSource for ChunkedSource {
+        type Error = std::convert::Infallible;
+
+        async fn next(&mut self) -> Option<Result<bytes::Bytes, Self::Error>> {
+            if self.contents.is_empty() {
+                return None;
+            }
+            let n = self.size.min(self.contents.len());
+            Some(Ok(self.contents.split_to(n)))
+        }
+    }
+
+    fn chunked(contents: &'static str, size: usize) -> ReadObjectResponse {
+        ReadObjectResponse::from_source(
+            ObjectHighlights::default(),
+            ChunkedSource {
+                contents: bytes::Bytes::from_static(contents.as_bytes()),
+                size,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_lines() -> anyhow::Result<()> {
+        // 行がチャンクの境界をまたぐ
+        for size in [1, 3, 7, 100] {
+            let mut lines = Lines::new(chunked("first\nsecond line\r\n\nlast", size));
+            let mut actual = Vec::new();
+            while let Some(line) = lines.next().await.transpose()? {
+                actual.push((line.number, line.offset, line.text));
+            }
+            assert_eq!(
+                actual,
+                vec![
+                    (1, 0, bytes::Bytes::from_static(b"first")),
+                    (2, 6, bytes::Bytes::from_static(b"second line")),
+                    (3, 19, bytes::Bytes::new()),
+                    (4, 20, bytes::Bytes::from_static(b"last")),
+                ],
+                "size={size}"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_grep() -> anyhow::Result<()> {
+        let mut grep = Lines::new(chunked("INFO a\nERROR b\nINFO c\nERROR d\n", 4)).grep("ERROR");
+        let mut actual = Vec::new();
+        while let Some(line) = grep.next().await.transpose()? {
+            actual.push(line.number);
+        }
+        assert_eq!(actual, vec![2, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_csv() {
+        assert_eq!(split_csv("a,b,,c").unwrap(), vec!["a", "b", "", "c"]);
+        assert_eq!(
+            split_csv(r#""a,b","say ""hi""",c"#).unwrap(),
+            vec!["a,b", r#"say "hi""#, "c"]
+        );
+        assert_eq!(split_csv("\"multi\nline"), None);
+    }
+
+    #[tokio::test]
+    async fn test_csv() -> anyhow::Result<()> {
+        let mut records = CsvRecords::new(Lines::new(chunked(
+            "id,comment\n1,\"hello\nworld\"\n\n2,plain\n3,\"open",
+            5,
+        )));
+        let mut actual = Vec::new();
+        let err = loop {
+            match records.next().await {
+                Some(Ok(record)) => actual.push((record.line, record.fields)),
+                Some(Err(e)) => break e,
+                None => panic!("the last record is not terminated"),
+            }
+        };
+        assert_eq!(
+            actual,
+            vec![
+                (1, vec!["id".to_string(), "comment".to_string()]),
+                (2, vec!["1".to_string(), "hello\nworld".to_string()]),
+                (5, vec!["2".to_string(), "plain".to_string()]),
+            ]
+        );
+        assert!(matches!(err, ScanError::Csv { line: 6, .. }), "{err:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ndjson() -> anyhow::Result<()> {
+        let mut records = NdjsonRecords::<serde_json::Value>::new(Lines::new(chunked(
+            "{\"a\":1}\n\n{\"a\":2}\n{broken\n",
+            3,
+        )));
+        assert_eq!(records.next().await.unwrap()?, serde_json::json!({"a": 1}));
+        assert_eq!(records.next().await.unwrap()?, serde_json::json!({"a": 2}));
+        let err = records
+            .next()
+            .await
+            .unwrap()
+            .expect_err("the line is broken");
+        // 4行目は17バイト目から始まり、`b`の位置でエラーになる
+        assert!(
+            matches!(
+                err,
+                ScanError::Json {
+                    line: 4,
+                    offset: 18,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+        assert!(records.next().await.is_none());
+        Ok(())
+    }
+
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    struct Event {
+        id: u32,
+        name: String,
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_stream() -> anyhow::Result<()> {
+        use futures::TryStreamExt;
+
+        let contents = "{\"id\":1,\"name\":\"a\"}\n{\"id\":2,\"name\":\"b\"}\n";
+        let records = NdjsonRecords::<Event>::new(Lines::new(chunked(contents, 5)));
+        let events: Vec<Event> = records.into_stream().try_collect().await?;
+        assert_eq!(
+            events,
+            vec![
+                Event {
+                    id: 1,
+                    name: "a".to_string()
+                },
+                Event {
+                    id: 2,
+                    name: "b".to_string()
+                },
+            ]
+        );
+
+        // 型が一致しない場合も、行番号とバイトオフセットを返す
+        let contents = "{\"id\":1,\"name\":\"a\"}\n{\"id\":\"2\",\"name\":\"b\"}\n";
+        let records = NdjsonRecords::<Event>::new(Lines::new(chunked(contents, 5)));
+        let err = records
+            .into_stream()
+            .try_collect::<Vec<_>>()
+            .await
+            .expect_err("id must be a number");
+        match err {
+            ScanError::Json { line, offset, .. } => {
+                assert_eq!(line, 2);
+                assert!((20..45).contains(&offset), "{offset}");
+            }
+            e => panic!("unexpected error {e:?}"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scanner() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let old = fake.insert("projects/_/buckets/my-bucket", "my-object", "a\nb\nc");
+        let client = gcs::client::Storage::from_stub(fake.clone());
+
+        let scanner = ObjectScanner::new(&client, "projects/_/buckets/my-bucket", "my-object");
+        assert_eq!(scanner.count_lines().await?, 3);
+        assert_eq!(scanner.head(3).await?, "a\nb");
+        assert_eq!(scanner.tail(3).await?, "b\nc");
+
+        // 世代を指定した場合は、その世代のみを読み込む
+        let scanner = scanner.with_generation(old.generation);
+        assert_eq!(scanner.count_lines().await?, 3);
+        fake.insert("projects/_/buckets/my-bucket", "my-object", "new");
+        assert!(scanner.count_lines().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_head_and_tail_empty() -> anyhow::Result<()> {
+        // オブジェクトが存在しなくてもエラーにならないため、リクエストを送信していない
+        let client = gcs::client::Storage::from_stub(FakeStorage::new());
+        let scanner = ObjectScanner::new(&client, "projects/_/buckets/my-bucket", "missing");
+        assert!(scanner.head(0).await?.is_empty());
+        assert!(scanner.tail(0).await?.is_empty());
+        assert!(scanner.head(1).await.is_err());
+        Ok(())
+    }
+}
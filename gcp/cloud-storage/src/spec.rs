@@ -0,0 +1,738 @@
+//! # 宣言的なバケットの設定
+//!
+//! `BucketSpec`はバケットのあるべき設定で、TOMLやYAMLなどserdeに対応した形式で記述する。
+//! `ensure_bucket`はバケットがなければ作成し、あれば現在の設定との差分のみを更新するため、何度実行しても同じ結果になる。
+//!
+//! ```toml
+//! location = "ASIA-NORTHEAST1"
+//! storage_class = "STANDARD"
+//! uniform_bucket_level_access = true
+//! versioning = true
+//! retention_seconds = 86400
+//!
+//! [labels]
+//! env = "dev"
+//!
+//! [[lifecycle]]
+//! action = { type = "SetStorageClass", storage_class = "NEARLINE" }
+//! age_days = 30
+//! matches_prefix = ["logs/"]
+//! ```
+//!
+//! ```yaml
+//! location: ASIA-NORTHEAST1
+//! uniform_bucket_level_access: true
+//! labels:
+//!   env: dev
+//! ```
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Display;
+
+use google_cloud_gax::error::rpc::Code;
+use google_cloud_storage::client::StorageControl;
+use google_cloud_storage::model::Bucket;
+use google_cloud_storage::model::bucket::iam_config::UniformBucketLevelAccess;
+use google_cloud_storage::model::bucket::lifecycle::Rule;
+use google_cloud_storage::model::bucket::lifecycle::rule::{Action, Condition};
+use google_cloud_storage::model::bucket::{
+    HierarchicalNamespace, IamConfig, Lifecycle, RetentionPolicy, Versioning,
+};
+use google_cloud_wkt::FieldMask;
+use serde::{Deserialize, Serialize};
+
+/// バケットのあるべき設定
+///
+/// 設定しない項目は、作成時はサーバーのデフォルトになり、更新時は変更しない。
+/// `labels`と`lifecycle`は設定した場合はバケットのすべてのラベル、ルールを表すため、記述しないものは削除する。
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BucketSpec {
+    /// 作成後は変更できない
+    pub location: Option<String>,
+    pub storage_class: Option<String>,
+    pub uniform_bucket_level_access: Option<bool>,
+    /// 作成後は変更できない。有効にする場合は`uniform_bucket_level_access`も有効にする必要がある。
+    pub hierarchical_namespace: Option<bool>,
+    pub labels: Option<BTreeMap<String, String>>,
+    pub versioning: Option<bool>,
+    pub lifecycle: Option<Vec<LifecycleRule>>,
+    /// 保持期間（秒）。`0`の場合は保持ポリシーを削除する。ロックされた保持ポリシーは変更できない。
+    pub retention_seconds: Option<i64>,
+}
+
+/// ライフサイクルのルール
+///
+/// 条件を設定しない場合は、すべてのオブジェクトに一致する。
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LifecycleRule {
+    pub action: LifecycleAction,
+    #[serde(default)]
+    pub age_days: Option<i32>,
+    #[serde(default)]
+    pub is_live: Option<bool>,
+    #[serde(default)]
+    pub num_newer_versions: Option<i32>,
+    #[serde(default)]
+    pub matches_storage_class: Vec<String>,
+    #[serde(default)]
+    pub matches_prefix: Vec<String>,
+    #[serde(default)]
+    pub matches_suffix: Vec<String>,
+}
+
+/// ライフサイクルのルールの操作
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum LifecycleAction {
+    Delete,
+    SetStorageClass { storage_class: String },
+    AbortIncompleteMultipartUpload,
+}
+
+impl BucketSpec {
+    /// TOMLから設定を読み込む。
+    pub fn from_toml(s: &str) -> Result<Self, SpecError> {
+        let spec: Self = toml::from_str(s).map_err(SpecError::Toml)?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    /// YAMLから設定を読み込む。
+    pub fn from_yaml(s: &str) -> Result<Self, SpecError> {
+        let spec: Self = serde_yaml_ng::from_str(s).map_err(SpecError::Yaml)?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    /// 設定の組み合わせが正しいか確認する。
+    pub fn validate(&self) -> Result<(), SpecError> {
+        if self.hierarchical_namespace == Some(true)
+            && self.uniform_bucket_level_access != Some(true)
+        {
+            return Err(SpecError::Invalid(
+                "hierarchical_namespace requires uniform_bucket_level_access".to_string(),
+            ));
+        }
+        if self.retention_seconds.is_some_and(|s| s < 0) {
+            return Err(SpecError::Invalid(
+                "retention_seconds must not be negative".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// バケットを作成するための`Bucket`を返す。
+    pub fn to_bucket(&self) -> Bucket {
+        let mut bucket = Bucket::new();
+        if let Some(enabled) = self.hierarchical_namespace {
+            bucket = bucket
+                .set_hierarchical_namespace(HierarchicalNamespace::new().set_enabled(enabled));
+        }
+        if let Some(labels) = &self.labels {
+            bucket = bucket.set_labels(labels.clone());
+        }
+        if let Some(location) = &self.location {
+            bucket = bucket.set_location(location);
+        }
+        if let Some(storage_class) = &self.storage_class {
+            bucket = bucket.set_storage_class(storage_class);
+        }
+        if let Some(enabled) = self.uniform_bucket_level_access {
+            bucket = bucket.set_iam_config(ubla(IamConfig::new(), enabled));
+        }
+        if let Some(enabled) = self.versioning {
+            bucket = bucket.set_versioning(Versioning::new().set_enabled(enabled));
+        }
+        if let Some(rules) = &self.lifecycle
+            && !rules.is_empty()
+        {
+            bucket = bucket.set_lifecycle(lifecycle(rules));
+        }
+        if let Some(seconds) = self.retention_seconds
+            && seconds > 0
+        {
+            bucket = bucket.set_retention_policy(retention(seconds));
+        }
+        bucket
+    }
+
+    /// 既存のバケットを設定に合わせるための差分を返す。
+    ///
+    /// 変更できない項目が異なる場合はエラーを返す。
+    pub fn diff(&self, bucket: &Bucket) -> Result<BucketDiff, SpecError> {
+        if let Some(location) = &self.location {
+            // サーバーは大文字のロケーションを返す
+            if !location.eq_ignore_ascii_case(&bucket.location) {
+                return Err(immutable("location", location, &bucket.location));
+            }
+        }
+        let hns = bucket
+            .hierarchical_namespace
+            .as_ref()
+            .is_some_and(|h| h.enabled);
+        if let Some(enabled) = self.hierarchical_namespace
+            && enabled != hns
+        {
+            return Err(immutable("hierarchical_namespace", enabled, hns));
+        }
+
+        let mut diff = BucketDiff {
+            bucket: Bucket::new().set_name(&bucket.name),
+            paths: Vec::new(),
+        };
+        if let Some(storage_class) = &self.storage_class
+            && *storage_class != bucket.storage_class
+        {
+            diff.bucket = diff.bucket.set_storage_class(storage_class);
+            diff.paths.push("storage_class");
+        }
+        let current = bucket.iam_config.clone().unwrap_or_default();
+        let enabled = current
+            .uniform_bucket_level_access
+            .as_ref()
+            .is_some_and(|u| u.enabled);
+        if let Some(ubla_enabled) = self.uniform_bucket_level_access
+            && ubla_enabled != enabled
+        {
+            // 公開アクセス防止などの他の設定は維持する
+            diff.bucket = diff.bucket.set_iam_config(ubla(current, ubla_enabled));
+            diff.paths.push("iam_config");
+        }
+        if let Some(labels) = &self.labels
+            && (labels.len() != bucket.labels.len()
+                || labels.iter().any(|(k, v)| bucket.labels.get(k) != Some(v)))
+        {
+            diff.bucket = diff.bucket.set_labels(labels.clone());
+            diff.paths.push("labels");
+        }
+        let versioning = bucket.versioning.as_ref().is_some_and(|v| v.enabled);
+        if let Some(enabled) = self.versioning
+            && enabled != versioning
+        {
+            diff.bucket = diff
+                .bucket
+                .set_versioning(Versioning::new().set_enabled(enabled));
+            diff.paths.push("versioning");
+        }
+        if let Some(rules) = &self.lifecycle {
+            let lifecycle = lifecycle(rules);
+            if lifecycle.rule != bucket.lifecycle.clone().unwrap_or_default().rule {
+                // ルールが空の場合は、ライフサイクルを削除する
+                if !lifecycle.rule.is_empty() {
+                    diff.bucket = diff.bucket.set_lifecycle(lifecycle);
+                }
+                diff.paths.push("lifecycle");
+            }
+        }
+        if let Some(expected) = self.retention_seconds {
+            let expected = (expected > 0).then_some(expected);
+            let policy = bucket.retention_policy.as_ref();
+            let seconds = policy
+                .and_then(|p| p.retention_duration.as_ref())
+                .map(|d| d.seconds());
+            if expected != seconds {
+                if policy.is_some_and(|p| p.is_locked) {
+                    return Err(immutable(
+                        "retention_seconds",
+                        format!("{expected:?}"),
+                        format!("{seconds:?}"),
+                    ));
+                }
+                // 保持期間が`0`の場合は、保持ポリシーを削除する
+                if let Some(seconds) = expected {
+                    diff.bucket = diff.bucket.set_retention_policy(retention(seconds));
+                }
+                diff.paths.push("retention_policy");
+            }
+        }
+        Ok(diff)
+    }
+}
+
+impl LifecycleRule {
+    fn to_rule(&self) -> Rule {
+        let action = match &self.action {
+            LifecycleAction::Delete => Action::new().set_type("Delete"),
+            LifecycleAction::SetStorageClass { storage_class } => Action::new()
+                .set_type("SetStorageClass")
+                .set_storage_class(storage_class),
+            LifecycleAction::AbortIncompleteMultipartUpload => {
+                Action::new().set_type("AbortIncompleteMultipartUpload")
+            }
+        };
+        let condition = Condition::new()
+            .set_or_clear_age_days(self.age_days)
+            .set_or_clear_is_live(self.is_live)
+            .set_or_clear_num_newer_versions(self.num_newer_versions)
+            .set_matches_storage_class(self.matches_storage_class.clone())
+            .set_matches_prefix(self.matches_prefix.clone())
+            .set_matches_suffix(self.matches_suffix.clone());
+        Rule::new().set_action(action).set_condition(condition)
+    }
+}
+
+fn lifecycle(rules: &[LifecycleRule]) -> Lifecycle {
+    Lifecycle::new().set_rule(rules.iter().map(LifecycleRule::to_rule))
+}
+
+fn ubla(iam_config: IamConfig, enabled: bool) -> IamConfig {
+    iam_config.set_uniform_bucket_level_access(UniformBucketLevelAccess::new().set_enabled(enabled))
+}
+
+fn retention(seconds: i64) -> RetentionPolicy {
+    RetentionPolicy::new().set_retention_duration(google_cloud_wkt::Duration::clamp(seconds, 0))
+}
+
+fn immutable(field: &'static str, expected: impl Display, actual: impl Display) -> SpecError {
+    SpecError::Immutable {
+        field,
+        expected: expected.to_string(),
+        actual: actual.to_string(),
+    }
+}
+
+/// 既存のバケットと設定の差分
+#[derive(Clone, Debug, PartialEq)]
+pub struct BucketDiff {
+    /// 更新するフィールドのみを設定したバケット
+    pub bucket: Bucket,
+    /// 更新するフィールドのパス
+    pub paths: Vec<&'static str>,
+}
+
+impl BucketDiff {
+    /// 更新が不要な場合は`true`を返す。
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// 更新するフィールドのマスクを返す。
+    pub fn update_mask(&self) -> FieldMask {
+        FieldMask::default().set_paths(self.paths.iter().copied())
+    }
+}
+
+/// 設定のエラー
+#[derive(Debug)]
+pub enum SpecError {
+    /// TOMLを読み込めない
+    Toml(toml::de::Error),
+    /// YAMLを読み込めない
+    Yaml(serde_yaml_ng::Error),
+    /// 設定の組み合わせが正しくない
+    Invalid(String),
+    /// 既存のバケットの変更できない項目が設定と異なる
+    Immutable {
+        field: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl Error for SpecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Toml(e) => Some(e),
+            Self::Yaml(e) => Some(e),
+            Self::Invalid(_) | Self::Immutable { .. } => None,
+        }
+    }
+}
+
+impl Display for SpecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Toml(e) => write!(f, "cannot parse the bucket spec: {e}"),
+            Self::Yaml(e) => write!(f, "cannot parse the bucket spec: {e}"),
+            Self::Invalid(message) => write!(f, "invalid bucket spec: {message}"),
+            Self::Immutable {
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{field} cannot be changed after creation: the spec has {expected}, the bucket has {actual}"
+            ),
+        }
+    }
+}
+
+/// バケットを設定に合わせる。
+///
+/// バケットがなければ作成し、あれば差分のみを更新する。更新は取得したメタ世代から変更されていない場合のみ行う。
+pub async fn ensure_bucket(
+    control: &StorageControl,
+    project_id: &str,
+    bucket_name: &str,
+    spec: &BucketSpec,
+) -> anyhow::Result<Bucket> {
+    spec.validate()?;
+    let name = crate::bucket_id(bucket_name);
+    let bucket = match control.get_bucket().set_name(&name).send().await {
+        Ok(bucket) => bucket,
+        Err(e) if e.status().is_some_and(|s| s.code == Code::NotFound) => {
+            let bucket = control
+                .create_bucket()
+                .set_parent("projects/_")
+                .set_bucket_id(bucket_name)
+                .set_bucket(
+                    spec.to_bucket()
+                        .set_project(format!("projects/{project_id}")),
+                )
+                .send()
+                .await?;
+            return Ok(bucket);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let diff = spec.diff(&bucket)?;
+    if diff.is_empty() {
+        return Ok(bucket);
+    }
+    let mask = diff.update_mask();
+    let bucket = control
+        .update_bucket()
+        .set_bucket(diff.bucket)
+        .set_update_mask(mask)
+        .set_if_metageneration_match(bucket.metageneration)
+        .send()
+        .await?;
+    Ok(bucket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BucketSpec, LifecycleAction, SpecError, ensure_bucket};
+    use gcs::client::StorageControl;
+    use gcs::model::bucket::iam_config::UniformBucketLevelAccess;
+    use gcs::model::bucket::{HierarchicalNamespace, IamConfig, RetentionPolicy, Versioning};
+    use gcs::model::{Bucket, CreateBucketRequest, GetBucketRequest, UpdateBucketRequest};
+    use google_cloud_gax as gax;
+    use google_cloud_storage as gcs;
+
+    const SPEC: &str = r#"
+location = "asia-northeast1"
+storage_class = "STANDARD"
+uniform_bucket_level_access = true
+versioning = true
+retention_seconds = 86400
+
+[labels]
+env = "dev"
+team = "storage"
+
+[[lifecycle]]
+action = { type = "SetStorageClass", storage_class = "NEARLINE" }
+age_days = 30
+matches_prefix = ["logs/"]
+
+[[lifecycle]]
+action = { type = "Delete" }
+num_newer_versions = 3
+"#;
+
+    const NAME: &str = "projects/_/buckets/my-bucket";
+
+    // 設定どおりに作成されたバケット
+    fn created(spec: &BucketSpec) -> Bucket {
+        spec.to_bucket()
+            .set_name(NAME)
+            .set_location("ASIA-NORTHEAST1")
+            .set_metageneration(7)
+    }
+
+    #[test]
+    fn test_from_toml() -> anyhow::Result<()> {
+        let spec = BucketSpec::from_toml(SPEC)?;
+        assert_eq!(spec.location.as_deref(), Some("asia-northeast1"));
+        assert_eq!(spec.labels.as_ref().map(|l| l.len()), Some(2));
+        let lifecycle = spec.lifecycle.as_deref().unwrap_or_default();
+        assert_eq!(
+            lifecycle[0].action,
+            LifecycleAction::SetStorageClass {
+                storage_class: "NEARLINE".to_string()
+            }
+        );
+        assert_eq!(lifecycle[1].num_newer_versions, Some(3));
+        assert_eq!(spec.hierarchical_namespace, None);
+
+        let err = BucketSpec::from_toml("unknown = 1").expect_err("unknown fields are rejected");
+        assert!(matches!(err, SpecError::Toml(_)), "{err:?}");
+        let err =
+            BucketSpec::from_toml("hierarchical_namespace = true").expect_err("HNS requires UBLA");
+        assert!(matches!(err, SpecError::Invalid(_)), "{err:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_yaml() -> anyhow::Result<()> {
+        let yaml = r#"
+location: asia-northeast1
+storage_class: STANDARD
+uniform_bucket_level_access: true
+versioning: true
+retention_seconds: 86400
+labels:
+  env: dev
+  team: storage
+lifecycle:
+  - action: { type: SetStorageClass, storage_class: NEARLINE }
+    age_days: 30
+    matches_prefix: ["logs/"]
+  - action: { type: Delete }
+    num_newer_versions: 3
+"#;
+        assert_eq!(BucketSpec::from_yaml(yaml)?, BucketSpec::from_toml(SPEC)?);
+
+        let err = BucketSpec::from_yaml("unknown: 1").expect_err("unknown fields are rejected");
+        assert!(matches!(err, SpecError::Yaml(_)), "{err:?}");
+        let err = BucketSpec::from_yaml("retention_seconds: -1")
+            .expect_err("negative retention is rejected");
+        assert!(matches!(err, SpecError::Invalid(_)), "{err:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_unchanged() -> anyhow::Result<()> {
+        let spec = BucketSpec::from_toml(SPEC)?;
+        assert!(spec.diff(&created(&spec))?.is_empty());
+        // 設定しない項目は比較しない
+        let bucket = created(&spec)
+            .set_storage_class("NEARLINE")
+            .set_versioning(Versioning::new().set_enabled(false));
+        let partial = BucketSpec {
+            location: None,
+            storage_class: None,
+            versioning: None,
+            ..spec.clone()
+        };
+        assert!(partial.diff(&bucket)?.is_empty());
+        assert!(BucketSpec::default().diff(&bucket)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_changed() -> anyhow::Result<()> {
+        let spec = BucketSpec::from_toml(SPEC)?;
+        let mut changed = spec.clone();
+        changed.storage_class = Some("NEARLINE".to_string());
+        changed.uniform_bucket_level_access = Some(false);
+        if let Some(labels) = changed.labels.as_mut() {
+            labels.insert("env".to_string(), "prod".to_string());
+        }
+        changed.versioning = Some(false);
+        if let Some(lifecycle) = changed.lifecycle.as_mut() {
+            lifecycle.pop();
+        }
+        changed.retention_seconds = Some(0);
+
+        let bucket = created(&spec).set_iam_config(
+            IamConfig::new()
+                .set_public_access_prevention("enforced")
+                .set_uniform_bucket_level_access(UniformBucketLevelAccess::new().set_enabled(true)),
+        );
+        let diff = changed.diff(&bucket)?;
+        assert_eq!(
+            diff.paths,
+            [
+                "storage_class",
+                "iam_config",
+                "labels",
+                "versioning",
+                "lifecycle",
+                "retention_policy"
+            ]
+        );
+        assert_eq!(diff.bucket.name, NAME);
+        assert_eq!(diff.bucket.storage_class, "NEARLINE");
+        assert_eq!(diff.bucket.labels["env"], "prod");
+        assert_eq!(diff.bucket.lifecycle.as_ref().unwrap().rule.len(), 1);
+        // 保持ポリシーを削除する
+        assert_eq!(diff.bucket.retention_policy, None);
+        // UBLA以外の設定は維持する
+        let iam_config = diff.bucket.iam_config.as_ref().unwrap();
+        assert_eq!(iam_config.public_access_prevention, "enforced");
+        assert!(
+            !iam_config
+                .uniform_bucket_level_access
+                .as_ref()
+                .unwrap()
+                .enabled
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_immutable() -> anyhow::Result<()> {
+        let spec = BucketSpec::from_toml(SPEC)?;
+        let bucket = created(&spec).set_location("US");
+        let err = spec.diff(&bucket).expect_err("location is immutable");
+        assert!(
+            matches!(
+                err,
+                SpecError::Immutable {
+                    field: "location",
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+
+        let bucket = created(&spec)
+            .set_hierarchical_namespace(HierarchicalNamespace::new().set_enabled(true));
+        // 設定しない場合は比較しない
+        assert!(spec.diff(&bucket).is_ok());
+        let flat = BucketSpec {
+            hierarchical_namespace: Some(false),
+            ..spec.clone()
+        };
+        let err = flat.diff(&bucket).expect_err("HNS is immutable");
+        assert!(
+            matches!(
+                err,
+                SpecError::Immutable {
+                    field: "hierarchical_namespace",
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+
+        let bucket = created(&spec).set_retention_policy(
+            RetentionPolicy::new()
+                .set_is_locked(true)
+                .set_retention_duration(google_cloud_wkt::Duration::clamp(3600, 0)),
+        );
+        let err = spec
+            .diff(&bucket)
+            .expect_err("locked retention is immutable");
+        assert!(
+            matches!(
+                err,
+                SpecError::Immutable {
+                    field: "retention_seconds",
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    mockall::mock! {
+        #[derive(Debug)]
+        StorageControl {}
+
+        impl gcs::stub::StorageControl for StorageControl {
+            async fn get_bucket(&self, req: GetBucketRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<Bucket>>;
+            async fn create_bucket(&self, req: CreateBucketRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<Bucket>>;
+            async fn update_bucket(&self, req: UpdateBucketRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<Bucket>>;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ensure_bucket_creates() -> anyhow::Result<()> {
+        let spec = BucketSpec::from_toml(SPEC)?;
+        let mut mock = MockStorageControl::new();
+        mock.expect_get_bucket()
+            .withf(|r, _| r.name == NAME)
+            .times(1)
+            .returning(|_, _| {
+                Err(gax::error::Error::service(
+                    gax::error::rpc::Status::default()
+                        .set_code(gax::error::rpc::Code::NotFound)
+                        .set_message("not found"),
+                ))
+            });
+        mock.expect_create_bucket()
+            .withf(|r, _| {
+                r.bucket_id == "my-bucket"
+                    && r.bucket
+                        .as_ref()
+                        .is_some_and(|b| b.project == "projects/my-project" && b.labels.len() == 2)
+            })
+            .times(1)
+            .returning(|r, _| {
+                Ok(gax::response::Response::from(
+                    r.bucket.unwrap().set_name(NAME),
+                ))
+            });
+        mock.expect_update_bucket().never();
+        let control = StorageControl::from_stub(mock);
+
+        let bucket = ensure_bucket(&control, "my-project", "my-bucket", &spec).await?;
+        assert_eq!(bucket.name, NAME);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ensure_bucket_patches() -> anyhow::Result<()> {
+        let spec = BucketSpec::from_toml(SPEC)?;
+        let mut changed = spec.clone();
+        changed.versioning = Some(false);
+
+        let existing = created(&spec);
+        let mut mock = MockStorageControl::new();
+        mock.expect_get_bucket()
+            .times(2)
+            .returning(move |_, _| Ok(gax::response::Response::from(existing.clone())));
+        mock.expect_create_bucket().never();
+        // 差分のみを、取得したメタ世代から変更されていない場合に更新する
+        mock.expect_update_bucket()
+            .withf(|r, _| {
+                r.if_metageneration_match == Some(7)
+                    && r.update_mask
+                        .as_ref()
+                        .is_some_and(|m| m.paths == ["versioning"])
+            })
+            .times(1)
+            .returning(|r, _| Ok(gax::response::Response::from(r.bucket.unwrap())));
+        let control = StorageControl::from_stub(mock);
+
+        let bucket = ensure_bucket(&control, "my-project", "my-bucket", &changed).await?;
+        assert!(!bucket.versioning.unwrap().enabled);
+        // 差分がなければ更新しない
+        let bucket = ensure_bucket(&control, "my-project", "my-bucket", &spec).await?;
+        assert_eq!(bucket.metageneration, 7);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ensure_bucket_keeps_unset() -> anyhow::Result<()> {
+        let existing = created(&BucketSpec::from_toml(SPEC)?).set_iam_config(
+            IamConfig::new().set_uniform_bucket_level_access(
+                UniformBucketLevelAccess::new().set_enabled(false),
+            ),
+        );
+        let mut mock = MockStorageControl::new();
+        mock.expect_get_bucket()
+            .times(1)
+            .returning(move |_, _| Ok(gax::response::Response::from(existing.clone())));
+        mock.expect_create_bucket().never();
+        // 設定しないラベル、ルール、保持ポリシーは更新しない
+        mock.expect_update_bucket()
+            .withf(|r, _| {
+                r.update_mask
+                    .as_ref()
+                    .is_some_and(|m| m.paths == ["iam_config"])
+            })
+            .times(1)
+            .returning(|r, _| Ok(gax::response::Response::from(r.bucket.unwrap())));
+        let control = StorageControl::from_stub(mock);
+
+        let spec = BucketSpec {
+            uniform_bucket_level_access: Some(true),
+            ..BucketSpec::default()
+        };
+        let bucket = ensure_bucket(&control, "my-project", "my-bucket", &spec).await?;
+        assert!(
+            bucket
+                .iam_config
+                .and_then(|c| c.uniform_bucket_level_access)
+                .is_some_and(|u| u.enabled)
+        );
+        Ok(())
+    }
+}
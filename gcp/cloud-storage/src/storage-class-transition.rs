@@ -0,0 +1,52 @@
+use std::time::{Duration, SystemTime};
+
+use gcs::client::StorageControl;
+use google_cloud_storage as gcs;
+
+use cloud_storage::{StorageClassTransitioner, TransitionRule, bucket_id};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+// 使用方法: storage-class-transition <bucket_name> [--apply]
+//
+// `--apply`を指定しない場合は、計画を表示するだけでオブジェクトは変更しない。
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let bucket_name = std::env::args().nth(1).unwrap();
+    let apply = std::env::args().any(|a| a == "--apply");
+
+    let control = StorageControl::builder().build().await?;
+    let transitioner = StorageClassTransitioner::new(&control, bucket_id(&bucket_name))
+        // 1GiB以上のオブジェクトはCOLDLINE
+        .with_rule(TransitionRule::new("COLDLINE").with_min_size(1024 * 1024 * 1024))
+        // 作成から30日以上経過したログはNEARLINE
+        .with_rule(
+            TransitionRule::new("NEARLINE")
+                .with_prefix("logs/")
+                .with_min_age(30 * DAY),
+        );
+
+    let plan = transitioner.plan(SystemTime::now()).await?;
+    for transition in &plan {
+        println!("{transition}");
+    }
+    println!("{} object(s) to transition", plan.len());
+    if !apply {
+        println!("dry run; pass --apply to rewrite the objects");
+        return Ok(());
+    }
+
+    let results = transitioner.apply(plan).await;
+    let mut failures = 0;
+    for result in &results {
+        match &result.outcome {
+            Ok(object) => println!("{}: {}", object.name, object.storage_class),
+            Err(e) => {
+                failures += 1;
+                println!("{}: FAILED {e:#}", result.transition.object.name);
+            }
+        }
+    }
+    println!("{} succeeded, {failures} failed", results.len() - failures);
+    Ok(())
+}
@@ -0,0 +1,26 @@
+//! 異常終了などで残った一時的なバケットを削除する。
+//!
+//! ```sh
+//! cargo run --package=cloud-storage --bin=sweep-temp-buckets -- <project-id>
+//! ```
+use std::time::Duration;
+
+use google_cloud_storage::client::StorageControl;
+
+use cloud_storage::sweep_temp_buckets;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let project_id = std::env::args().nth(1).unwrap();
+
+    // 実行中のプロセスのバケットを削除しないように、作成から1時間以上経過したバケットのみ削除
+    let control = StorageControl::builder().build().await?;
+    let report = sweep_temp_buckets(&control, &project_id, Duration::from_secs(60 * 60)).await?;
+    for bucket in &report.deleted {
+        println!("deleted {bucket}");
+    }
+    for failure in &report.failures {
+        println!("failed to delete {}: {:#}", failure.bucket, failure.error);
+    }
+    Ok(())
+}
@@ -0,0 +1,397 @@
+//! # 一時的なバケット
+//!
+//! `TempBucket`は一意な名前のバケットを作成して、`delete`で中身を空にしてから削除する。
+//! `Drop`では削除しないため、`delete`を必ず呼び出す。
+//! 作成したバケットには`TEMP_BUCKET_LABEL`のラベルを付けるため、異常終了で残ったバケットを`sweep_temp_buckets`で削除できる。
+//!
+//! ```ignore
+//! let bucket = TempBucket::create(&control, PROJECT_ID).await?;
+//! client.write_object(bucket.name(), "hello.txt", "hello").send_buffered().await?;
+//! bucket.delete().await?;
+//! ```
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{StreamExt as _, TryStreamExt as _};
+use google_cloud_gax::paginator::ItemPaginator as _;
+use google_cloud_storage::client::StorageControl;
+use google_cloud_storage::model::Bucket;
+
+use crate::{BucketSpec, DEFAULT_MAX_TRANSFERS};
+
+/// 一時的なバケットに付けるラベルのキー
+pub const TEMP_BUCKET_LABEL: &str = "temp-bucket";
+
+/// 一時的なバケットに付けるラベルの値
+pub const TEMP_BUCKET_LABEL_VALUE: &str = "true";
+
+/// 一時的なバケット
+///
+/// ドロップしてもバケットは削除しない。使い終わったら`delete`を呼び出して削除し、
+/// 呼び出せずに残ったバケットは`sweep_temp_buckets`で削除する。
+#[derive(Debug)]
+pub struct TempBucket {
+    control: StorageControl,
+    bucket: Bucket,
+}
+
+impl TempBucket {
+    /// UBLAを有効にした一時的なバケットを作成する。
+    pub async fn create(control: &StorageControl, project_id: &str) -> anyhow::Result<Self> {
+        let spec = BucketSpec {
+            uniform_bucket_level_access: Some(true),
+            ..BucketSpec::default()
+        };
+        Self::create_with_spec(control, project_id, &spec).await
+    }
+
+    /// 設定を指定して一時的なバケットを作成する。
+    ///
+    /// バケットの名前は`my-bucket-{uuid}`になり、設定のラベルに`TEMP_BUCKET_LABEL`を追加する。
+    pub async fn create_with_spec(
+        control: &StorageControl,
+        project_id: &str,
+        spec: &BucketSpec,
+    ) -> anyhow::Result<Self> {
+        spec.validate()?;
+        let mut spec = spec.clone();
+        spec.labels.get_or_insert_default().insert(
+            TEMP_BUCKET_LABEL.to_string(),
+            TEMP_BUCKET_LABEL_VALUE.to_string(),
+        );
+        let bucket = control
+            .create_bucket()
+            .set_parent("projects/_")
+            .set_bucket_id(format!("my-bucket-{}", uuid::Uuid::new_v4()))
+            .set_bucket(
+                spec.to_bucket()
+                    .set_project(format!("projects/{project_id}")),
+            )
+            .send()
+            .await?;
+        Ok(Self {
+            control: control.clone(),
+            bucket,
+        })
+    }
+
+    /// 作成したバケットを返す。
+    pub fn bucket(&self) -> &Bucket {
+        &self.bucket
+    }
+
+    /// `projects/_/buckets/{bucket_name}`形式のバケット名を返す。
+    pub fn name(&self) -> &str {
+        &self.bucket().name
+    }
+
+    /// バケットを空にしてから削除する。
+    pub async fn delete(self) -> anyhow::Result<()> {
+        empty_and_delete_bucket(&self.control, &self.bucket).await
+    }
+}
+
+/// バケットのすべてのオブジェクトのすべての世代とフォルダを削除してから、バケットを削除する。
+pub async fn empty_and_delete_bucket(
+    control: &StorageControl,
+    bucket: &Bucket,
+) -> anyhow::Result<()> {
+    control
+        .list_objects()
+        .set_parent(&bucket.name)
+        .set_versions(true)
+        .by_item()
+        .into_stream()
+        .map_err(anyhow::Error::from)
+        .try_for_each_concurrent(DEFAULT_MAX_TRANSFERS, |object| async move {
+            control
+                .delete_object()
+                .set_bucket(&bucket.name)
+                .set_object(&object.name)
+                .set_generation(object.generation)
+                .send()
+                .await?;
+            Ok(())
+        })
+        .await?;
+
+    // フォルダは階層的名前空間のバケットにのみある
+    if bucket
+        .hierarchical_namespace
+        .as_ref()
+        .is_some_and(|h| h.enabled)
+    {
+        let mut folders: Vec<String> = control
+            .list_folders()
+            .set_parent(&bucket.name)
+            .by_item()
+            .into_stream()
+            .map_ok(|f| f.name)
+            .try_collect()
+            .await?;
+        // 空でないフォルダは削除できないため、深いフォルダから削除する
+        folders.sort_by_key(|name| std::cmp::Reverse(name.matches('/').count()));
+        for name in folders {
+            control.delete_folder().set_name(name).send().await?;
+        }
+    }
+
+    control
+        .delete_bucket()
+        .set_name(&bucket.name)
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// `now`の時点で作成から`older_than`以上経過した、一時的なバケットの場合に`true`を返す。
+///
+/// 実行中の他のプロセスのバケットを削除しないように、作成されたばかりのバケットは対象にしない。
+pub fn is_orphaned(bucket: &Bucket, older_than: Duration, now: SystemTime) -> bool {
+    let labelled = bucket
+        .labels
+        .get(TEMP_BUCKET_LABEL)
+        .is_some_and(|v| v == TEMP_BUCKET_LABEL_VALUE);
+    let expired = bucket
+        .create_time
+        .as_ref()
+        .and_then(|t| u64::try_from(t.seconds()).ok())
+        .and_then(|s| now.duration_since(UNIX_EPOCH + Duration::from_secs(s)).ok())
+        .is_some_and(|age| age >= older_than);
+    labelled && expired
+}
+
+/// 削除に失敗したバケット
+#[derive(Debug)]
+pub struct SweepFailure {
+    pub bucket: String,
+    pub error: anyhow::Error,
+}
+
+/// 一時的なバケットの掃除の結果
+#[derive(Debug, Default)]
+pub struct SweepReport {
+    /// 削除したバケット名
+    pub deleted: Vec<String>,
+    pub failures: Vec<SweepFailure>,
+}
+
+/// プロジェクトのバケットから、作成から`older_than`以上経過した一時的なバケットを削除する。
+///
+/// バケットの一覧に失敗した場合はエラーを返す。一部のバケットの削除に失敗しても、残りのバケットの削除は続ける。
+pub async fn sweep_temp_buckets(
+    control: &StorageControl,
+    project_id: &str,
+    older_than: Duration,
+) -> anyhow::Result<SweepReport> {
+    let now = SystemTime::now();
+    let orphans: Vec<Bucket> = control
+        .list_buckets()
+        .set_parent(format!("projects/{project_id}"))
+        .by_item()
+        .into_stream()
+        .try_filter(|b| std::future::ready(is_orphaned(b, older_than, now)))
+        .try_collect()
+        .await?;
+    let results = futures::stream::iter(orphans)
+        .map(|bucket| async move {
+            let result = empty_and_delete_bucket(control, &bucket).await;
+            (bucket.name, result)
+        })
+        .buffer_unordered(DEFAULT_MAX_TRANSFERS)
+        .collect::<Vec<_>>()
+        .await;
+    let mut report = SweepReport::default();
+    for (bucket, result) in results {
+        match result {
+            Ok(()) => report.deleted.push(bucket),
+            Err(error) => report.failures.push(SweepFailure { bucket, error }),
+        }
+    }
+    report.deleted.sort();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TEMP_BUCKET_LABEL, TempBucket, is_orphaned, sweep_temp_buckets};
+    use gcs::client::StorageControl;
+    use gcs::model::bucket::HierarchicalNamespace;
+    use gcs::model::{
+        Bucket, CreateBucketRequest, DeleteBucketRequest, DeleteFolderRequest, DeleteObjectRequest,
+        Folder, ListBucketsRequest, ListBucketsResponse, ListFoldersRequest, ListFoldersResponse,
+        ListObjectsRequest, ListObjectsResponse, Object,
+    };
+    use google_cloud_gax as gax;
+    use google_cloud_storage as gcs;
+    use google_cloud_wkt::Timestamp;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    mockall::mock! {
+        #[derive(Debug)]
+        StorageControl {}
+
+        impl gcs::stub::StorageControl for StorageControl {
+            async fn create_bucket(&self, req: CreateBucketRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<Bucket>>;
+            async fn delete_bucket(&self, req: DeleteBucketRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<()>>;
+            async fn list_buckets(&self, req: ListBucketsRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<ListBucketsResponse>>;
+            async fn list_objects(&self, req: ListObjectsRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<ListObjectsResponse>>;
+            async fn delete_object(&self, req: DeleteObjectRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<()>>;
+            async fn list_folders(&self, req: ListFoldersRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<ListFoldersResponse>>;
+            async fn delete_folder(&self, req: DeleteFolderRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<()>>;
+        }
+    }
+
+    fn temp_bucket(name: &str, created: SystemTime) -> Bucket {
+        let seconds = created.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        Bucket::new()
+            .set_name(format!("projects/_/buckets/{name}"))
+            .set_labels([(TEMP_BUCKET_LABEL, "true")])
+            .set_create_time(Timestamp::clamp(seconds, 0))
+    }
+
+    #[test]
+    fn test_is_orphaned() {
+        let now = SystemTime::now();
+        assert!(is_orphaned(&temp_bucket("old", now - 2 * HOUR), HOUR, now));
+        // 作成されたばかりのバケットは、実行中のプロセスが使っている可能性がある
+        assert!(!is_orphaned(&temp_bucket("new", now), HOUR, now));
+        // ラベルがないバケットは削除しない
+        let bucket = temp_bucket("unlabelled", now - 2 * HOUR).set_labels([("env", "dev")]);
+        assert!(!is_orphaned(&bucket, HOUR, now));
+    }
+
+    #[tokio::test]
+    async fn test_create_and_delete() -> anyhow::Result<()> {
+        let deleted = Arc::new(Mutex::new(Vec::new()));
+        let mut mock = MockStorageControl::new();
+        mock.expect_create_bucket()
+            .withf(|r, _| {
+                r.bucket_id.starts_with("my-bucket-")
+                    && r.bucket.as_ref().is_some_and(|b| {
+                        b.project == "projects/my-project"
+                            && b.labels.get(TEMP_BUCKET_LABEL).map(String::as_str) == Some("true")
+                    })
+            })
+            .times(1)
+            .returning(|r, _| {
+                let bucket = r
+                    .bucket
+                    .unwrap()
+                    .set_name(format!("projects/_/buckets/{}", r.bucket_id))
+                    .set_hierarchical_namespace(HierarchicalNamespace::new().set_enabled(true));
+                Ok(gax::response::Response::from(bucket))
+            });
+        // すべての世代を一覧する
+        mock.expect_list_objects()
+            .withf(|r, _| r.versions)
+            .times(1)
+            .returning(|_, _| {
+                let objects =
+                    [("a.txt", 1), ("a.txt", 2), ("dir/b.txt", 3)].map(|(name, generation)| {
+                        Object::new().set_name(name).set_generation(generation)
+                    });
+                Ok(gax::response::Response::from(
+                    ListObjectsResponse::new().set_objects(objects),
+                ))
+            });
+        mock.expect_delete_object().times(3).returning({
+            let deleted = deleted.clone();
+            move |r, _| {
+                deleted
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}#{}", r.object, r.generation));
+                Ok(gax::response::Response::from(()))
+            }
+        });
+        mock.expect_list_folders().times(1).returning(|r, _| {
+            let folders = ["dir/", "dir/sub/"]
+                .map(|f| Folder::new().set_name(format!("{}/folders/{f}", r.parent)));
+            Ok(gax::response::Response::from(
+                ListFoldersResponse::new().set_folders(folders),
+            ))
+        });
+        mock.expect_delete_folder().times(2).returning({
+            let deleted = deleted.clone();
+            move |r, _| {
+                deleted.lock().unwrap().push(r.name);
+                Ok(gax::response::Response::from(()))
+            }
+        });
+        mock.expect_delete_bucket().times(1).returning({
+            let deleted = deleted.clone();
+            move |r, _| {
+                deleted.lock().unwrap().push(r.name);
+                Ok(gax::response::Response::from(()))
+            }
+        });
+        let control = StorageControl::from_stub(mock);
+
+        let bucket = TempBucket::create(&control, "my-project").await?;
+        let name = bucket.name().to_string();
+        bucket.delete().await?;
+
+        let mut deleted = deleted.lock().unwrap().clone();
+        // オブジェクトは並行して削除する
+        deleted[..3].sort();
+        assert_eq!(
+            deleted,
+            vec![
+                "a.txt#1".to_string(),
+                "a.txt#2".to_string(),
+                "dir/b.txt#3".to_string(),
+                format!("{name}/folders/dir/sub/"),
+                format!("{name}/folders/dir/"),
+                name,
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sweep() -> anyhow::Result<()> {
+        let now = SystemTime::now();
+        let mut mock = MockStorageControl::new();
+        mock.expect_list_buckets()
+            .withf(|r, _| r.parent == "projects/my-project")
+            .times(1)
+            .returning(move |_, _| {
+                let buckets = [
+                    temp_bucket("orphan-1", now - 3 * HOUR),
+                    temp_bucket("orphan-2", now - 2 * HOUR),
+                    temp_bucket("running", now),
+                    Bucket::new().set_name("projects/_/buckets/permanent"),
+                ];
+                Ok(gax::response::Response::from(
+                    ListBucketsResponse::new().set_buckets(buckets),
+                ))
+            });
+        mock.expect_list_objects()
+            .returning(|_, _| Ok(gax::response::Response::from(ListObjectsResponse::new())));
+        mock.expect_list_folders().never();
+        mock.expect_delete_bucket()
+            .withf(|r, _| r.name == "projects/_/buckets/orphan-1")
+            .times(1)
+            .returning(|_, _| Ok(gax::response::Response::from(())));
+        mock.expect_delete_bucket()
+            .withf(|r, _| r.name == "projects/_/buckets/orphan-2")
+            .times(1)
+            .returning(|_, _| {
+                Err(gax::error::Error::service(
+                    gax::error::rpc::Status::default()
+                        .set_code(gax::error::rpc::Code::PermissionDenied)
+                        .set_message("denied"),
+                ))
+            });
+        let control = StorageControl::from_stub(mock);
+
+        let report = sweep_temp_buckets(&control, "my-project", HOUR).await?;
+        assert_eq!(report.deleted, vec!["projects/_/buckets/orphan-1"]);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].bucket, "projects/_/buckets/orphan-2");
+        Ok(())
+    }
+}
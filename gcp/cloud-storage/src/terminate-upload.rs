@@ -3,8 +3,9 @@ use std::fmt::Display;
 
 use google_cloud_storage::client::{Storage, StorageControl};
 use google_cloud_storage::streaming_source::StreamingSource;
+use tokio_util::sync::CancellationToken;
 
-use cloud_storage::{PROJECT_ID, create_bucket};
+use cloud_storage::{CancelError, CancellableSource, PROJECT_ID, create_bucket, queue};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -24,6 +25,33 @@ async fn main() -> anyhow::Result<()> {
     assert!(err.is_serialization(), "{err:?}");
     assert!(err.source().is_some_and(|e| e.is::<MyError>()), "{err:?}");
 
+    // トークンをキャンセルして、アップロードを中止
+    let (sender, source) = queue(1024 * 1024);
+    let token = CancellationToken::new();
+    let upload = client
+        .write_object(
+            &bucket.name,
+            "expect-cancel",
+            CancellableSource::new(source, token.clone()),
+        )
+        .send_buffered();
+    let task = tokio::spawn(upload);
+    sender
+        .send(bytes::Bytes::from_static(b"this upload will be cancelled"))
+        .await?;
+    token.cancel();
+    let err = task
+        .await?
+        .expect_err("the token is supposed to cancel the upload");
+    println!("Cancelled upload {err:?}");
+    assert!(err.is_serialization(), "{err:?}");
+    assert!(
+        err.source()
+            .and_then(|e| e.downcast_ref::<CancelError>())
+            .is_some_and(CancelError::is_cancelled),
+        "{err:?}"
+    );
+
     Ok(())
 }
 
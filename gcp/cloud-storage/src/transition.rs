@@ -0,0 +1,429 @@
+//! # ルールに基づいたストレージクラスの変更
+//!
+//! オブジェクトの名前のプレフィックス、作成からの経過時間、サイズに基づいたルールで、変更先のストレージクラスを決める。
+//! ルールの評価は一覧したオブジェクトと現在時刻のみを使用するため、偽の一覧でテストできる。
+//!
+//! ルールは先頭から順に評価して、最初に一致したルールを使用する。
+//! ストレージクラスは`STANDARD`、`NEARLINE`、`COLDLINE`、`ARCHIVE`の順に低温になり、より低温のクラスへのみ変更する。
+use std::fmt::Display;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{StreamExt as _, TryStreamExt as _};
+use google_cloud_gax::paginator::ItemPaginator as _;
+use google_cloud_storage::client::StorageControl;
+use google_cloud_storage::model::Object;
+
+use crate::rewrite::destination;
+use crate::{DEFAULT_MAX_TRANSFERS, RewriteDriver};
+
+/// ストレージクラスを変更するルール
+///
+/// すべての条件に一致したオブジェクトを`storage_class`に変更する。条件を設定しない場合は、すべてのオブジェクトに一致する。
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransitionRule {
+    pub storage_class: String,
+    pub prefix: Option<String>,
+    /// 作成からの経過時間の下限
+    pub min_age: Option<Duration>,
+    /// サイズの下限（バイト）
+    pub min_size: Option<u64>,
+}
+
+impl TransitionRule {
+    pub fn new(storage_class: impl Into<String>) -> Self {
+        Self {
+            storage_class: storage_class.into(),
+            prefix: None,
+            min_age: None,
+            min_size: None,
+        }
+    }
+
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn with_min_age(mut self, min_age: Duration) -> Self {
+        self.min_age = Some(min_age);
+        self
+    }
+
+    pub fn with_min_size(mut self, min_size: u64) -> Self {
+        self.min_size = Some(min_size);
+        self
+    }
+
+    /// `now`の時点で、オブジェクトがルールの条件に一致する場合は`true`を返す。
+    ///
+    /// 作成時刻がないオブジェクトは、経過時間の条件に一致しない。
+    pub fn matches(&self, object: &Object, now: SystemTime) -> bool {
+        let prefix = self
+            .prefix
+            .as_ref()
+            .is_none_or(|p| object.name.starts_with(p.as_str()));
+        let age = self.min_age.is_none_or(|min_age| {
+            object
+                .create_time
+                .as_ref()
+                .and_then(|t| u64::try_from(t.seconds()).ok())
+                .and_then(|s| now.duration_since(UNIX_EPOCH + Duration::from_secs(s)).ok())
+                .is_some_and(|age| age >= min_age)
+        });
+        let size = self
+            .min_size
+            .is_none_or(|min_size| object.size as u64 >= min_size);
+        prefix && age && size
+    }
+}
+
+/// オブジェクトのストレージクラスの変更
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+    pub object: Object,
+    /// 変更先のストレージクラス
+    pub storage_class: String,
+    /// 一致したルールの位置
+    pub rule: usize,
+}
+
+impl Display for Transition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let current = match self.object.storage_class.as_str() {
+            "" => "(default)",
+            class => class,
+        };
+        write!(
+            f,
+            "{} (generation={}, size={}): {current} -> {} by rule #{}",
+            self.object.name,
+            self.object.generation,
+            self.object.size,
+            self.storage_class,
+            self.rule
+        )
+    }
+}
+
+/// オブジェクトに最初に一致したルールで、ストレージクラスの変更を返す。
+///
+/// 一致したルールのストレージクラスが現在のクラスより低温でない場合は、変更しない。
+pub fn evaluate_transition(
+    rules: &[TransitionRule],
+    object: &Object,
+    now: SystemTime,
+) -> Option<Transition> {
+    let (index, rule) = rules
+        .iter()
+        .enumerate()
+        .find(|(_, r)| r.matches(object, now))?;
+    (temperature(&rule.storage_class) > temperature(&object.storage_class)).then(|| Transition {
+        object: object.clone(),
+        storage_class: rule.storage_class.clone(),
+        rule: index,
+    })
+}
+
+/// オブジェクトの一覧から、ストレージクラスの変更の計画を作成する。
+pub fn plan_transitions<'o>(
+    rules: &[TransitionRule],
+    objects: impl IntoIterator<Item = &'o Object>,
+    now: SystemTime,
+) -> Vec<Transition> {
+    objects
+        .into_iter()
+        .filter_map(|o| evaluate_transition(rules, o, now))
+        .collect()
+}
+
+// ストレージクラスの温度（大きいほど低温）
+//
+// 未設定のクラスはバケットのデフォルト、従来のクラスは`STANDARD`と同じとみなす。
+fn temperature(storage_class: &str) -> u8 {
+    match storage_class {
+        "NEARLINE" => 1,
+        "COLDLINE" => 2,
+        "ARCHIVE" => 3,
+        _ => 0,
+    }
+}
+
+/// ストレージクラスの変更の結果
+#[derive(Debug)]
+pub struct TransitionResult {
+    pub transition: Transition,
+    /// 書き換えた後のオブジェクト
+    pub outcome: anyhow::Result<Object>,
+}
+
+/// バケットのオブジェクトにルールを適用する。
+pub struct StorageClassTransitioner<'a> {
+    control: &'a StorageControl,
+    bucket: String,
+    prefix: String,
+    rules: Vec<TransitionRule>,
+    max_transfers: usize,
+}
+
+impl<'a> StorageClassTransitioner<'a> {
+    /// `bucket`は`projects/_/buckets/{bucket_name}`形式のバケット名を指定する。
+    pub fn new(control: &'a StorageControl, bucket: impl Into<String>) -> Self {
+        Self {
+            control,
+            bucket: bucket.into(),
+            prefix: String::new(),
+            rules: Vec::new(),
+            max_transfers: DEFAULT_MAX_TRANSFERS,
+        }
+    }
+
+    /// 一覧するオブジェクトのプレフィックスを設定する。
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// ルールを追加する。先に追加したルールを優先する。
+    pub fn with_rule(mut self, rule: TransitionRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// 同時に書き換えるオブジェクトの最大数を設定する。
+    pub fn with_max_transfers(mut self, max_transfers: usize) -> Self {
+        assert!(max_transfers > 0, "max transfers must be positive");
+        self.max_transfers = max_transfers;
+        self
+    }
+
+    /// オブジェクトを一覧して、`now`の時点の変更の計画を作成する。オブジェクトは変更しない。
+    pub async fn plan(&self, now: SystemTime) -> anyhow::Result<Vec<Transition>> {
+        let objects: Vec<Object> = self
+            .control
+            .list_objects()
+            .set_parent(&self.bucket)
+            .set_prefix(&self.prefix)
+            .by_item()
+            .into_stream()
+            .try_collect()
+            .await?;
+        Ok(plan_transitions(&self.rules, &objects, now))
+    }
+
+    /// 計画したストレージクラスの変更を、オブジェクトを書き換えて適用する。
+    ///
+    /// 一覧した後にオブジェクトが上書きされた場合は、新しい世代を変更せずにエラーにする。
+    pub async fn apply(&self, transitions: Vec<Transition>) -> Vec<TransitionResult> {
+        futures::stream::iter(transitions)
+            .map(|transition| async move {
+                let outcome = self.rewrite(&transition).await;
+                TransitionResult {
+                    transition,
+                    outcome,
+                }
+            })
+            .buffer_unordered(self.max_transfers)
+            .collect()
+            .await
+    }
+
+    async fn rewrite(&self, transition: &Transition) -> anyhow::Result<Object> {
+        let object = &transition.object;
+        let mut builder = self
+            .control
+            .rewrite_object()
+            .set_source_bucket(&self.bucket)
+            .set_source_object(&object.name)
+            .set_source_generation(object.generation)
+            .set_destination_bucket(&self.bucket)
+            .set_destination_name(&object.name)
+            .set_if_generation_match(object.generation)
+            .set_destination(destination(object, &transition.storage_class));
+        // 書き換え先には`kms_key`を指定できないため、同じ鍵を別に指定する
+        // オブジェクトの`kms_key`は鍵のバージョンを含むため、鍵の名前のみを指定する
+        if !object.kms_key.is_empty() {
+            let key = object
+                .kms_key
+                .split_once("/cryptoKeyVersions/")
+                .map_or(object.kms_key.as_str(), |(key, _)| key);
+            builder = builder.set_destination_kms_key(key);
+        }
+        RewriteDriver::new(builder).run().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StorageClassTransitioner, TransitionRule, evaluate_transition, plan_transitions};
+    use gcs::client::StorageControl;
+    use gcs::model::{
+        ListObjectsRequest, ListObjectsResponse, Object, RewriteObjectRequest, RewriteResponse,
+    };
+    use google_cloud_gax as gax;
+    use google_cloud_storage as gcs;
+    use google_cloud_wkt::Timestamp;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    const KMS_KEY: &str = "projects/my-project/locations/us/keyRings/my-ring/cryptoKeys/my-key";
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    // 2025-01-01T00:00:00Z
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_735_689_600)
+    }
+
+    fn object(name: &str, days: u64, size: u64, storage_class: &str) -> Object {
+        let created = 1_735_689_600 - (days * DAY.as_secs()) as i64;
+        Object::new()
+            .set_bucket("projects/_/buckets/my-bucket")
+            .set_name(name)
+            .set_generation(days as i64 + 1)
+            .set_size(size as i64)
+            .set_storage_class(storage_class)
+            .set_create_time(Timestamp::clamp(created, 0))
+    }
+
+    fn rules() -> Vec<TransitionRule> {
+        vec![
+            TransitionRule::new("COLDLINE").with_min_size(GIB),
+            TransitionRule::new("NEARLINE")
+                .with_prefix("logs/")
+                .with_min_age(30 * DAY),
+        ]
+    }
+
+    #[test]
+    fn test_matches() {
+        let rule = TransitionRule::new("NEARLINE")
+            .with_prefix("logs/")
+            .with_min_age(30 * DAY);
+        assert!(rule.matches(&object("logs/a", 30, 1, "STANDARD"), now()));
+        assert!(!rule.matches(&object("logs/a", 29, 1, "STANDARD"), now()));
+        assert!(!rule.matches(&object("data/a", 31, 1, "STANDARD"), now()));
+        // 作成時刻がないオブジェクトは、経過時間の条件に一致しない
+        let unknown = Object::new().set_name("logs/a");
+        assert!(!rule.matches(&unknown, now()));
+        assert!(TransitionRule::new("NEARLINE").matches(&unknown, now()));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let rules = rules();
+        // 最初に一致したルールを使用する
+        let t = evaluate_transition(&rules, &object("logs/big", 40, 2 * GIB, ""), now()).unwrap();
+        assert_eq!((t.storage_class.as_str(), t.rule), ("COLDLINE", 0));
+        let t = evaluate_transition(&rules, &object("logs/old", 40, 1, "STANDARD"), now()).unwrap();
+        assert_eq!((t.storage_class.as_str(), t.rule), ("NEARLINE", 1));
+        // 一致するルールがない
+        assert_eq!(
+            evaluate_transition(&rules, &object("logs/new", 1, 1, "STANDARD"), now()),
+            None
+        );
+        // 同じクラスや、より高温のクラスには変更しない
+        assert_eq!(
+            evaluate_transition(&rules, &object("logs/old", 40, 1, "NEARLINE"), now()),
+            None
+        );
+        assert_eq!(
+            evaluate_transition(&rules, &object("logs/old", 40, 1, "ARCHIVE"), now()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_plan() {
+        let objects = [
+            object("data/big", 1, 2 * GIB, "STANDARD"),
+            object("data/small", 100, 1, "STANDARD"),
+            object("logs/new", 1, 1, "STANDARD"),
+            object("logs/old", 31, 1, "STANDARD"),
+        ];
+        let plan = plan_transitions(&rules(), &objects, now());
+        let names = plan
+            .iter()
+            .map(|t| (t.object.name.as_str(), t.storage_class.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![("data/big", "COLDLINE"), ("logs/old", "NEARLINE")]
+        );
+        assert_eq!(
+            plan[1].to_string(),
+            "logs/old (generation=32, size=1): STANDARD -> NEARLINE by rule #1"
+        );
+    }
+
+    mockall::mock! {
+        #[derive(Debug)]
+        StorageControl {}
+
+        impl gcs::stub::StorageControl for StorageControl {
+            async fn list_objects(&self, req: ListObjectsRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<ListObjectsResponse>>;
+            async fn rewrite_object(&self, req: RewriteObjectRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<RewriteResponse>>;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply() -> anyhow::Result<()> {
+        let mut mock = MockStorageControl::new();
+        mock.expect_list_objects()
+            .withf(|r, _| r.prefix == "logs/")
+            .times(1)
+            .returning(|_, _| {
+                let objects = [
+                    object("logs/new", 1, 1, "STANDARD"),
+                    object("logs/old", 31, 1, "STANDARD")
+                        .set_content_type("text/plain")
+                        .set_metadata([("source", "app")])
+                        .set_kms_key(format!("{KMS_KEY}/cryptoKeyVersions/1")),
+                ];
+                Ok(gax::response::Response::from(
+                    ListObjectsResponse::new().set_objects(objects),
+                ))
+            });
+        mock.expect_rewrite_object()
+            .withf(|r, _| {
+                r.source_object == "logs/old"
+                    && r.destination_name == "logs/old"
+                    && r.source_generation == 32
+                    && r.if_generation_match == Some(32)
+                    && r.destination_kms_key == KMS_KEY
+                    && r.destination.as_ref().is_some_and(|o| {
+                        o.storage_class == "NEARLINE"
+                            && o.content_type == "text/plain"
+                            && o.metadata.get("source").map(String::as_str) == Some("app")
+                            && o.generation == 0
+                            && o.create_time.is_none()
+                    })
+            })
+            .times(1)
+            .returning(|r, _| {
+                let object = r.destination.unwrap().set_name(r.destination_name);
+                Ok(gax::response::Response::from(
+                    RewriteResponse::new().set_done(true).set_resource(object),
+                ))
+            });
+        let control = StorageControl::from_stub(mock);
+
+        let transitioner = StorageClassTransitioner::new(&control, "projects/_/buckets/my-bucket")
+            .with_prefix("logs/")
+            .with_rule(
+                TransitionRule::new("NEARLINE")
+                    .with_prefix("logs/")
+                    .with_min_age(30 * DAY),
+            );
+        let plan = transitioner.plan(now()).await?;
+        assert_eq!(plan.len(), 1);
+        let results = transitioner.apply(plan).await;
+        assert_eq!(results.len(), 1);
+        let object = results[0].outcome.as_ref().expect("the rewrite succeeds");
+        assert_eq!(object.storage_class, "NEARLINE");
+        assert_eq!(object.content_type, "text/plain");
+        assert_eq!(
+            object.metadata.get("source").map(String::as_str),
+            Some("app")
+        );
+        Ok(())
+    }
+}
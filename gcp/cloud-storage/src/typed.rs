@@ -0,0 +1,294 @@
+//! # 型付きのオブジェクトの書き込み
+//!
+//! serdeでシリアライズした値をオブジェクトに書き込み、`content_type`を設定する。
+//!
+//! NDJSONとbincodeは、レコードを1つずつエンコードして`StreamingSource`に送るため、すべてのレコードをメモリに保持しない。
+//! エンコードは別のタスクで実行して、チャネルを通じてアップロードに渡す。
+use std::error::Error;
+use std::fmt::Display;
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt as _};
+use google_cloud_storage as gcs;
+use google_cloud_storage::client::Storage;
+use google_cloud_storage::model::Object;
+use google_cloud_storage::streaming_source::StreamingSource;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, Receiver};
+
+/// JSONのContent-Type
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// NDJSONのContent-Type
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// bincodeのContent-Type
+pub const BINCODE_CONTENT_TYPE: &str = "application/x-bincode";
+
+// エンコードしたレコードをまとめて送るサイズ（256KiB）
+const CHUNK_SIZE: usize = 256 * 1024;
+
+// エンコーダーとアップロードの間のチャネルの容量
+//
+// メモリに保持するのは、おおよそ`CHUNK_SIZE * (CHANNEL_CAPACITY + 1)`バイトまでになる。
+const CHANNEL_CAPACITY: usize = 4;
+
+/// レコードのエンコードのエラー
+///
+/// アップロードは`err.is_serialization()`が`true`のエラーで失敗し、`source()`をこの型にダウンキャストできる。
+#[derive(Debug)]
+pub enum EncodeError {
+    Json(serde_json::Error),
+    Bincode(bincode::error::EncodeError),
+}
+
+impl Error for EncodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Json(e) => Some(e),
+            Self::Bincode(e) => Some(e),
+        }
+    }
+}
+
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Json(e) => write!(f, "cannot encode the record as JSON: {e}"),
+            Self::Bincode(e) => write!(f, "cannot encode the record as bincode: {e}"),
+        }
+    }
+}
+
+/// `value`をJSONとして書き込む。
+pub async fn write_json<T, V>(
+    client: &Storage<T>,
+    bucket: &str,
+    object: &str,
+    value: &V,
+) -> gcs::Result<Object>
+where
+    T: gcs::stub::Storage + 'static,
+    V: Serialize + ?Sized,
+{
+    let contents = serde_json::to_vec(value)
+        .map_err(EncodeError::Json)
+        .map_err(gcs::Error::ser)?;
+    client
+        .write_object(bucket, object, Bytes::from(contents))
+        .set_content_type(JSON_CONTENT_TYPE)
+        .send_buffered()
+        .await
+}
+
+/// `records`を1行に1つずつJSONとして書き込む。
+///
+/// イテレーターから書き込む場合は`futures::stream::iter`で`Stream`に変換する。
+pub async fn write_ndjson<T, S>(
+    client: &Storage<T>,
+    bucket: &str,
+    object: &str,
+    records: S,
+) -> gcs::Result<Object>
+where
+    T: gcs::stub::Storage + 'static,
+    S: Stream + Send + 'static,
+    S::Item: Serialize + Send,
+{
+    let source = RecordSource::spawn(records, |record, buffer| {
+        serde_json::to_writer(&mut *buffer, record).map_err(EncodeError::Json)?;
+        buffer.push(b'\n');
+        Ok(())
+    });
+    client
+        .write_object(bucket, object, source)
+        .set_content_type(NDJSON_CONTENT_TYPE)
+        .send_buffered()
+        .await
+}
+
+/// `records`をbincodeで続けて書き込む。
+///
+/// 読み込む場合は、`bincode::serde::decode_from_slice`で先頭から1つずつデコードする。
+pub async fn write_bincode<T, S>(
+    client: &Storage<T>,
+    bucket: &str,
+    object: &str,
+    records: S,
+) -> gcs::Result<Object>
+where
+    T: gcs::stub::Storage + 'static,
+    S: Stream + Send + 'static,
+    S::Item: Serialize + Send,
+{
+    let source = RecordSource::spawn(records, |record, buffer| {
+        bincode::serde::encode_into_std_write(record, buffer, bincode::config::standard())
+            .map_err(EncodeError::Bincode)?;
+        Ok(())
+    });
+    client
+        .write_object(bucket, object, source)
+        .set_content_type(BINCODE_CONTENT_TYPE)
+        .send_buffered()
+        .await
+}
+
+// エンコーダーのタスクから、エンコードしたレコードを受け取るソース
+#[derive(Debug)]
+struct RecordSource(Receiver<Result<Bytes, EncodeError>>);
+
+impl RecordSource {
+    fn spawn<S, F>(records: S, mut encode: F) -> Self
+    where
+        S: Stream + Send + 'static,
+        S::Item: Send,
+        F: FnMut(&S::Item, &mut Vec<u8>) -> Result<(), EncodeError> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        tokio::spawn(async move {
+            let mut records = std::pin::pin!(records);
+            let mut buffer = Vec::new();
+            while let Some(record) = records.next().await {
+                if let Err(e) = encode(&record, &mut buffer) {
+                    // アップロードを中止する
+                    let _ = sender.send(Err(e)).await;
+                    return;
+                }
+                if buffer.len() >= CHUNK_SIZE {
+                    let chunk = Bytes::from(std::mem::take(&mut buffer));
+                    if sender.send(Ok(chunk)).await.is_err() {
+                        // アップロードが失敗して、受信側が閉じられた
+                        return;
+                    }
+                }
+            }
+            if !buffer.is_empty() {
+                let _ = sender.send(Ok(Bytes::from(buffer))).await;
+            }
+        });
+        Self(receiver)
+    }
+}
+
+impl StreamingSource for RecordSource {
+    type Error = EncodeError;
+
+    async fn next(&mut self) -> Option<Result<Bytes, Self::Error>> {
+        self.0.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BINCODE_CONTENT_TYPE, EncodeError, JSON_CONTENT_TYPE, NDJSON_CONTENT_TYPE, write_bincode,
+        write_json, write_ndjson,
+    };
+    use crate::FakeStorage;
+    use gcs::client::Storage;
+    use google_cloud_storage as gcs;
+    use std::error::Error as _;
+
+    const BUCKET: &str = "projects/_/buckets/my-bucket";
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Event {
+        id: u32,
+        name: String,
+    }
+
+    fn events(n: u32) -> impl Iterator<Item = Event> {
+        (0..n).map(|id| Event {
+            id,
+            name: format!("event-{id}"),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_write_json() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        let event = Event {
+            id: 1,
+            name: "a".to_string(),
+        };
+        let object = write_json(&client, BUCKET, "event.json", &event).await?;
+        assert_eq!(object.content_type, JSON_CONTENT_TYPE);
+        let contents = fake.contents(BUCKET, "event.json").unwrap();
+        assert_eq!(serde_json::from_slice::<Event>(&contents)?, event);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_ndjson() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        // 複数のチャンクに分かれる数のレコード
+        let records = futures::stream::iter(events(20_000));
+        let object = write_ndjson(&client, BUCKET, "events.ndjson", records).await?;
+        assert_eq!(object.content_type, NDJSON_CONTENT_TYPE);
+
+        let contents = fake.contents(BUCKET, "events.ndjson").unwrap();
+        assert!(contents.len() > super::CHUNK_SIZE);
+        let actual = contents
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(serde_json::from_slice::<Event>)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(actual, events(20_000).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_bincode() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        let records = futures::stream::iter(events(100));
+        let object = write_bincode(&client, BUCKET, "events.bin", records).await?;
+        assert_eq!(object.content_type, BINCODE_CONTENT_TYPE);
+
+        let contents = fake.contents(BUCKET, "events.bin").unwrap();
+        let mut rest = &contents[..];
+        let mut actual = Vec::new();
+        while !rest.is_empty() {
+            let (event, n): (Event, usize) =
+                bincode::serde::decode_from_slice(rest, bincode::config::standard())?;
+            actual.push(event);
+            rest = &rest[n..];
+        }
+        assert_eq!(actual, events(100).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    // シリアライズに常に失敗するレコード
+    struct Broken;
+
+    impl serde::Serialize for Broken {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("broken record"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encode_error() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        let records = futures::stream::iter([None, Some(Broken)]);
+        let err = write_ndjson(&client, BUCKET, "broken.ndjson", records)
+            .await
+            .expect_err("the record cannot be encoded");
+        assert!(err.is_serialization(), "{err:?}");
+        let source = err
+            .source()
+            .and_then(|e| e.downcast_ref::<EncodeError>())
+            .expect("the source is EncodeError");
+        assert!(matches!(source, EncodeError::Json(_)), "{source:?}");
+        // オブジェクトは作成されない
+        assert!(fake.object(BUCKET, "broken.ndjson").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,484 @@
+//! # 巨大なファイルの並行複合アップロード
+//!
+//! ファイルをパートに分割して、それぞれのパートを一時オブジェクトとして並行してアップロードした後、
+//! `compose_object`で1つのオブジェクトに合成する。
+//!
+//! 1回の`compose_object`で合成できるオブジェクトは32個までであるため、パートが32個を超える場合は、
+//! 32個ずつ中間オブジェクトに合成することを、オブジェクトが32個以下になるまで繰り返す。
+//! 一時オブジェクトは、アップロードの成否にかかわらず削除する。
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::{StreamExt as _, TryStreamExt as _};
+use google_cloud_storage as gcs;
+use google_cloud_storage::client::{Storage, StorageControl};
+use google_cloud_storage::model::Object;
+use google_cloud_storage::model::compose_object_request::SourceObject;
+use google_cloud_storage::streaming_source::{Seek, SizeHint, StreamingSource};
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _};
+
+use crate::download::stripes;
+
+/// 既定のパートサイズ（32MiB）
+pub const DEFAULT_PART_SIZE: u64 = 32 * 1024 * 1024;
+
+/// 1回の`compose_object`で合成できるオブジェクトの最大数
+pub const MAX_COMPOSE_SOURCES: usize = 32;
+
+/// 並行複合アップロードの結果
+#[derive(Clone, Debug)]
+pub struct UploadReport {
+    /// アップロードしたオブジェクト
+    pub object: Object,
+    /// アップロードしたバイト数
+    pub bytes: u64,
+    /// アップロードにかかった時間
+    pub elapsed: Duration,
+    /// パートの数
+    pub parts: usize,
+    /// 削除に失敗した一時オブジェクトの名前
+    pub orphaned: Vec<String>,
+}
+
+/// ファイルをパートに分割して、並行してアップロードした後に1つのオブジェクトに合成する。
+pub struct ParallelUploader<'a, T>
+where
+    T: gcs::stub::Storage + 'static,
+{
+    client: &'a Storage<T>,
+    control: &'a StorageControl,
+    source: PathBuf,
+    bucket: String,
+    object: String,
+    part_size: u64,
+    max_in_flight: usize,
+}
+
+impl<'a, T> ParallelUploader<'a, T>
+where
+    T: gcs::stub::Storage + 'static,
+{
+    /// `bucket`は`projects/_/buckets/{bucket_name}`形式のバケット名を指定する。
+    pub fn new(
+        client: &'a Storage<T>,
+        control: &'a StorageControl,
+        source: impl Into<PathBuf>,
+        bucket: impl Into<String>,
+        object: impl Into<String>,
+    ) -> Self {
+        Self {
+            client,
+            control,
+            source: source.into(),
+            bucket: bucket.into(),
+            object: object.into(),
+            part_size: DEFAULT_PART_SIZE,
+            max_in_flight: crate::DEFAULT_MAX_IN_FLIGHT,
+        }
+    }
+
+    /// パートサイズを設定する。
+    pub fn with_part_size(mut self, part_size: u64) -> Self {
+        assert!(part_size > 0, "part size must be positive");
+        self.part_size = part_size;
+        self
+    }
+
+    /// 同時にアップロードするパートの最大数を設定する。
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        assert!(max_in_flight > 0, "max in flight must be positive");
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// ファイルをアップロードする。
+    pub async fn upload(self) -> anyhow::Result<UploadReport> {
+        let start = Instant::now();
+        let size = tokio::fs::metadata(&self.source).await?.len();
+        let parts = stripes(size, self.part_size, self.part_size);
+
+        // 作成した一時オブジェクトを記録して、成否にかかわらず削除
+        let temporaries = Mutex::new(Vec::new());
+        let prefix = format!("{}.parts-{}", self.object, uuid::Uuid::new_v4());
+        let result = self.upload_and_compose(&prefix, &parts, &temporaries).await;
+        let orphaned = self.cleanup(temporaries.into_inner().unwrap()).await;
+        let object = result?;
+
+        Ok(UploadReport {
+            object,
+            bytes: size,
+            elapsed: start.elapsed(),
+            parts: parts.len(),
+            orphaned,
+        })
+    }
+
+    async fn upload_and_compose(
+        &self,
+        prefix: &str,
+        parts: &[(u64, u64)],
+        temporaries: &Mutex<Vec<Object>>,
+    ) -> anyhow::Result<Object> {
+        // 空のファイルは、パートに分割せずにアップロード
+        if parts.is_empty() {
+            let object = self
+                .client
+                .write_object(&self.bucket, &self.object, bytes::Bytes::new())
+                .send_unbuffered()
+                .await?;
+            return Ok(object);
+        }
+
+        // パートを並行してアップロード
+        let mut sources = futures::stream::iter(parts.iter().enumerate())
+            .map(|(i, &(offset, len))| async move {
+                let payload = PartSource::open(&self.source, offset, len).await?;
+                let object = self
+                    .client
+                    .write_object(&self.bucket, format!("{prefix}/part-{i:05}"), payload)
+                    .send_unbuffered()
+                    .await?;
+                temporaries.lock().unwrap().push(object.clone());
+                Ok::<_, anyhow::Error>((i, object))
+            })
+            .buffer_unordered(self.max_in_flight)
+            .try_collect::<Vec<_>>()
+            .await?;
+        sources.sort_by_key(|(i, _)| *i);
+        let mut sources = sources.into_iter().map(|(_, o)| o).collect::<Vec<_>>();
+
+        // 合成するオブジェクトが32個以下になるまで、中間オブジェクトに合成
+        let mut level = 0;
+        while sources.len() > MAX_COMPOSE_SOURCES {
+            sources = futures::stream::iter(sources.chunks(MAX_COMPOSE_SOURCES).enumerate())
+                .map(|(i, chunk)| async move {
+                    let name = format!("{prefix}/compose-{level}-{i:05}");
+                    let object = self.compose(chunk, name).await?;
+                    temporaries.lock().unwrap().push(object.clone());
+                    Ok::<_, anyhow::Error>(object)
+                })
+                .buffered(self.max_in_flight)
+                .try_collect()
+                .await?;
+            level += 1;
+        }
+        self.compose(&sources, self.object.clone()).await
+    }
+
+    async fn compose(&self, sources: &[Object], name: String) -> anyhow::Result<Object> {
+        let object = self
+            .control
+            .compose_object()
+            .set_destination(Object::new().set_bucket(&self.bucket).set_name(name))
+            .set_source_objects(sources.iter().map(|o| {
+                SourceObject::new()
+                    .set_name(&o.name)
+                    .set_generation(o.generation)
+            }))
+            .send()
+            .await?;
+        Ok(object)
+    }
+
+    // 一時オブジェクトを削除して、削除に失敗したオブジェクトの名前を返す
+    async fn cleanup(&self, temporaries: Vec<Object>) -> Vec<String> {
+        futures::stream::iter(temporaries)
+            .map(|o| async move {
+                self.control
+                    .delete_object()
+                    .set_bucket(&self.bucket)
+                    .set_object(&o.name)
+                    .set_generation(o.generation)
+                    .send()
+                    .await
+                    .err()
+                    .map(|_| o.name)
+            })
+            .buffer_unordered(self.max_in_flight)
+            .filter_map(std::future::ready)
+            .collect()
+            .await
+    }
+}
+
+// 1回の`next`で読み込む最大バイト数
+const READ_SIZE: u64 = 256 * 1024;
+
+// ファイルの`offset`の位置から`len`バイトを、パート全体をメモリに読み込まずに返すソース
+struct PartSource {
+    file: tokio::fs::File,
+    offset: u64,
+    len: u64,
+    // パートの先頭からの位置
+    position: u64,
+}
+
+impl PartSource {
+    async fn open(path: &Path, offset: u64, len: u64) -> std::io::Result<Self> {
+        let mut file = tokio::fs::File::open(path).await?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        Ok(Self {
+            file,
+            offset,
+            len,
+            position: 0,
+        })
+    }
+}
+
+impl StreamingSource for PartSource {
+    type Error = std::io::Error;
+
+    async fn next(&mut self) -> Option<Result<bytes::Bytes, Self::Error>> {
+        let remaining = self.len - self.position;
+        if remaining == 0 {
+            return None;
+        }
+        let mut buffer = vec![0; remaining.min(READ_SIZE) as usize];
+        match self.file.read_exact(&mut buffer).await {
+            Ok(_) => {
+                self.position += buffer.len() as u64;
+                Some(Ok(bytes::Bytes::from(buffer)))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    async fn size_hint(&self) -> Result<SizeHint, Self::Error> {
+        Ok(SizeHint::with_exact(self.len))
+    }
+}
+
+impl Seek for PartSource {
+    type Error = std::io::Error;
+
+    async fn seek(&mut self, offset: u64) -> Result<(), Self::Error> {
+        let offset = offset.min(self.len);
+        self.file
+            .seek(std::io::SeekFrom::Start(self.offset + offset))
+            .await?;
+        self.position = offset;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_COMPOSE_SOURCES, ParallelUploader};
+    use gcs::client::{Storage, StorageControl};
+    use gcs::model::{ComposeObjectRequest, DeleteObjectRequest, Object};
+    use gcs::model_ext::WriteObjectRequest;
+    use gcs::request_options::RequestOptions;
+    use gcs::streaming_source::{Seek, StreamingSource};
+    use google_cloud_gax as gax;
+    use google_cloud_storage as gcs;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    const BUCKET: &str = "projects/_/buckets/my-bucket";
+
+    // アップロードしたパートの内容を記録するスタブ
+    //
+    // `fail_at`を設定した場合は、その番目（1から数える）のアップロードが失敗する。
+    #[derive(Clone, Debug, Default)]
+    struct PartStorage {
+        parts: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+        writes: Arc<Mutex<usize>>,
+        fail_at: Option<usize>,
+    }
+
+    impl PartStorage {
+        fn contents(&self, name: &str) -> Option<Vec<u8>> {
+            self.parts.lock().unwrap().get(name).cloned()
+        }
+    }
+
+    impl gcs::stub::Storage for PartStorage {
+        async fn write_object_unbuffered<P>(
+            &self,
+            mut payload: P,
+            req: WriteObjectRequest,
+            _options: RequestOptions,
+        ) -> gcs::Result<Object>
+        where
+            P: StreamingSource + Seek + Send + Sync + 'static,
+        {
+            let n = {
+                let mut writes = self.writes.lock().unwrap();
+                *writes += 1;
+                *writes
+            };
+            if self.fail_at == Some(n) {
+                return Err(error(gax::error::rpc::Code::PermissionDenied));
+            }
+            let mut contents = Vec::new();
+            while let Some(b) = payload.next().await.transpose().map_err(gcs::Error::ser)? {
+                contents.extend_from_slice(&b);
+            }
+            let resource = req.spec.resource.unwrap_or_default();
+            let object = resource.set_generation(1).set_size(contents.len() as i64);
+            self.parts
+                .lock()
+                .unwrap()
+                .insert(object.name.clone(), contents);
+            Ok(object)
+        }
+    }
+
+    mockall::mock! {
+        #[derive(Debug)]
+        StorageControl {}
+
+        impl gcs::stub::StorageControl for StorageControl {
+            async fn compose_object(&self, req: ComposeObjectRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<Object>>;
+            async fn delete_object(&self, req: DeleteObjectRequest, _options: gax::options::RequestOptions) -> gax::Result<gax::response::Response<()>>;
+        }
+    }
+
+    fn compose(
+        req: ComposeObjectRequest,
+        _options: gax::options::RequestOptions,
+    ) -> gax::Result<gax::response::Response<Object>> {
+        let object = req.destination.unwrap().set_generation(1);
+        Ok(gax::response::Response::from(object))
+    }
+
+    fn error(code: gax::error::rpc::Code) -> gax::error::Error {
+        gax::error::Error::service(
+            gax::error::rpc::Status::default()
+                .set_code(code)
+                .set_message("failed"),
+        )
+    }
+
+    // 削除した一時オブジェクトの名前を記録する
+    fn expect_deletes(mock: &mut MockStorageControl) -> Arc<Mutex<Vec<String>>> {
+        let deleted = Arc::new(Mutex::new(Vec::new()));
+        mock.expect_delete_object().returning({
+            let deleted = deleted.clone();
+            move |r, _| {
+                deleted.lock().unwrap().push(r.object);
+                Ok(gax::response::Response::from(()))
+            }
+        });
+        deleted
+    }
+
+    async fn source(len: usize) -> anyhow::Result<std::path::PathBuf> {
+        let path = std::env::temp_dir().join(format!("upload-{}", uuid::Uuid::new_v4()));
+        let contents = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        tokio::fs::write(&path, contents).await?;
+        Ok(path)
+    }
+
+    #[tokio::test]
+    async fn test_upload_composes_in_batches() -> anyhow::Result<()> {
+        let stub = PartStorage::default();
+        let client = Storage::from_stub(stub.clone());
+        let mut mock = MockStorageControl::new();
+        let sources = Arc::new(Mutex::new(Vec::new()));
+        mock.expect_compose_object().returning({
+            let sources = sources.clone();
+            move |r, o| {
+                let name = r.destination.as_ref().unwrap().name.clone();
+                sources.lock().unwrap().push((name, r.source_objects.len()));
+                compose(r, o)
+            }
+        });
+        let deleted = expect_deletes(&mut mock);
+        let control = StorageControl::from_stub(mock);
+
+        // 70個のパートは、32個ずつ3個の中間オブジェクトに合成してから、1つに合成する
+        let path = source(700).await?;
+        let report = ParallelUploader::new(&client, &control, &path, BUCKET, "my-object")
+            .with_part_size(10)
+            .with_max_in_flight(4)
+            .upload()
+            .await?;
+        assert_eq!(report.parts, 70);
+        assert_eq!(report.bytes, 700);
+        assert_eq!(report.object.name, "my-object");
+        assert!(report.orphaned.is_empty());
+
+        let mut sources = sources.lock().unwrap().clone();
+        let last = sources.pop().unwrap();
+        assert_eq!(last, ("my-object".to_string(), 3));
+        assert_eq!(
+            sources.iter().map(|(_, n)| *n).collect::<Vec<_>>(),
+            vec![MAX_COMPOSE_SOURCES, MAX_COMPOSE_SOURCES, 6]
+        );
+        assert!(sources.iter().all(|(name, _)| name.contains("/compose-0-")));
+
+        // パートは範囲ごとにアップロードする
+        let prefix = sources[0].0.split("/compose-").next().unwrap().to_string();
+        assert_eq!(
+            stub.contents(&format!("{prefix}/part-00001")).unwrap(),
+            (10..20).map(|i| i as u8).collect::<Vec<_>>()
+        );
+        // パートと中間オブジェクトをすべて削除する
+        assert_eq!(deleted.lock().unwrap().len(), 73);
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_part_failure_cleanup() -> anyhow::Result<()> {
+        // 3番目のパートのアップロードは失敗する
+        let stub = PartStorage {
+            fail_at: Some(3),
+            ..PartStorage::default()
+        };
+        let client = Storage::from_stub(stub);
+        let mut mock = MockStorageControl::new();
+        mock.expect_compose_object().never();
+        let deleted = expect_deletes(&mut mock);
+        let control = StorageControl::from_stub(mock);
+
+        let path = source(50).await?;
+        ParallelUploader::new(&client, &control, &path, BUCKET, "my-object")
+            .with_part_size(10)
+            .with_max_in_flight(1)
+            .upload()
+            .await
+            .expect_err("the part upload fails");
+        // アップロードしたパートのみを削除する
+        let mut deleted = deleted.lock().unwrap().clone();
+        deleted.sort();
+        assert_eq!(deleted.len(), 2, "{deleted:?}");
+        assert!(deleted[0].ends_with("/part-00000"), "{deleted:?}");
+        assert!(deleted[1].ends_with("/part-00001"), "{deleted:?}");
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_compose_failure_cleanup() -> anyhow::Result<()> {
+        let client = Storage::from_stub(PartStorage::default());
+        let mut mock = MockStorageControl::new();
+        // 2番目の中間オブジェクトの合成は失敗する
+        mock.expect_compose_object()
+            .withf(|r, _| {
+                r.destination
+                    .as_ref()
+                    .is_some_and(|d| d.name.ends_with("/compose-0-00001"))
+            })
+            .returning(|_, _| Err(error(gax::error::rpc::Code::PermissionDenied)));
+        mock.expect_compose_object().returning(compose);
+        let deleted = expect_deletes(&mut mock);
+        let control = StorageControl::from_stub(mock);
+
+        let path = source(400).await?;
+        ParallelUploader::new(&client, &control, &path, BUCKET, "my-object")
+            .with_part_size(10)
+            .upload()
+            .await
+            .expect_err("the compose fails");
+        // 40個のパートと、合成できた中間オブジェクトを削除する
+        let deleted = deleted.lock().unwrap().clone();
+        assert_eq!(deleted.len(), 41, "{deleted:?}");
+        assert!(deleted.iter().any(|n| n.ends_with("/compose-0-00000")));
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+}
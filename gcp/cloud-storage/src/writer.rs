@@ -0,0 +1,308 @@
+//! # `AsyncWrite`でオブジェクトに書き込むアダプター
+//!
+//! `GcsWriter`はアップロードを別のタスクで実行して、書き込んだデータをチャネルを通じてアップロードに渡す。
+//! `tokio::io::copy`や、圧縮のエンコーダーなど`AsyncWrite`を受け取るライブラリにそのまま渡せる。
+//!
+//! ```ignore
+//! let mut writer = GcsWriter::new(&client, "projects/_/buckets/my-bucket", "my-object");
+//! tokio::io::copy(&mut reader, &mut writer).await?;
+//! let object = writer.finish().await?;
+//! ```
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, ready};
+
+use bytes::{Bytes, BytesMut};
+use google_cloud_storage as gcs;
+use google_cloud_storage::client::Storage;
+use google_cloud_storage::model::Object;
+use google_cloud_storage::streaming_source::StreamingSource;
+use tokio::io::{AsyncWrite, AsyncWriteExt as _};
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::task::JoinHandle;
+use tokio_util::sync::PollSender;
+
+// 書き込んだデータをまとめて送るサイズ（256KiB）
+const CHUNK_SIZE: usize = 256 * 1024;
+
+// `GcsWriter`とアップロードの間のチャネルの容量
+const CHANNEL_CAPACITY: usize = 4;
+
+/// オブジェクトに書き込む`AsyncWrite`
+///
+/// `shutdown`でアップロードを完了する。`shutdown`の前にドロップした場合は、アップロードを中止する。
+/// `flush`はデータをアップロードのタスクに渡すだけで、オブジェクトに書き込まれたことを保証しない。
+pub struct GcsWriter {
+    sender: Option<PollSender<Bytes>>,
+    buffer: BytesMut,
+    // `shutdown`でデータをすべて書き込んだことを、ソースに通知する
+    finished: Arc<AtomicBool>,
+    upload: Option<JoinHandle<gcs::Result<Object>>>,
+    object: Option<Object>,
+}
+
+impl GcsWriter {
+    /// `bucket`は`projects/_/buckets/{bucket_name}`形式のバケット名を指定する。
+    pub fn new<T>(client: &Storage<T>, bucket: impl Into<String>, object: impl Into<String>) -> Self
+    where
+        T: gcs::stub::Storage + 'static,
+    {
+        let upload = |source| client.write_object(bucket, object, source).send_buffered();
+        Self::with_upload(upload)
+    }
+
+    /// `upload`に渡すソースでアップロードを開始する。
+    ///
+    /// `content_type`や前提条件を設定する場合に使用する。
+    ///
+    /// ```ignore
+    /// let writer = GcsWriter::with_upload(|source| {
+    ///     client
+    ///         .write_object(bucket, "data.csv", source)
+    ///         .set_content_type("text/csv")
+    ///         .send_buffered()
+    /// });
+    /// ```
+    pub fn with_upload<F, U>(upload: F) -> Self
+    where
+        F: FnOnce(WriterSource) -> U,
+        U: Future<Output = gcs::Result<Object>> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let finished = Arc::new(AtomicBool::new(false));
+        let source = WriterSource {
+            receiver,
+            finished: finished.clone(),
+        };
+        Self {
+            sender: Some(PollSender::new(sender)),
+            buffer: BytesMut::new(),
+            finished,
+            upload: Some(tokio::spawn(upload(source))),
+            object: None,
+        }
+    }
+
+    /// アップロードを完了して、作成したオブジェクトを返す。
+    pub async fn finish(mut self) -> gcs::Result<Object> {
+        match self.shutdown().await {
+            Ok(()) => Ok(self.object.take().expect("the upload has completed")),
+            Err(e) => match e.into_inner().map(|e| e.downcast::<gcs::Error>()) {
+                Some(Ok(e)) => Err(*e),
+                Some(Err(e)) => Err(gcs::Error::io(e)),
+                None => Err(gcs::Error::io(io::Error::from(io::ErrorKind::BrokenPipe))),
+            },
+        }
+    }
+
+    /// `shutdown`が完了した後に、作成したオブジェクトを返す。
+    pub fn object(&self) -> Option<&Object> {
+        self.object.as_ref()
+    }
+
+    // バッファーのデータをアップロードに送る
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.buffer.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        let Some(sender) = self.sender.as_mut() else {
+            return Poll::Ready(Err(closed()));
+        };
+        if ready!(sender.poll_reserve(cx)).is_err() {
+            // アップロードが失敗して、受信側が閉じられた
+            self.sender = None;
+            ready!(self.poll_upload(cx))?;
+            return Poll::Ready(Err(closed()));
+        }
+        let chunk = self.buffer.split().freeze();
+        sender.send_item(chunk).map_err(|_| closed())?;
+        Poll::Ready(Ok(()))
+    }
+
+    // アップロードの完了を待つ
+    fn poll_upload(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(upload) = self.upload.as_mut() else {
+            return Poll::Ready(if self.object.is_some() {
+                Ok(())
+            } else {
+                Err(closed())
+            });
+        };
+        let result = ready!(Pin::new(upload).poll(cx));
+        self.upload = None;
+        match result {
+            Ok(Ok(object)) => {
+                self.object = Some(object);
+                Poll::Ready(Ok(()))
+            }
+            Ok(Err(e)) => Poll::Ready(Err(io::Error::other(e))),
+            Err(e) => Poll::Ready(Err(io::Error::other(e))),
+        }
+    }
+}
+
+impl AsyncWrite for GcsWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.sender.is_none() {
+            return Poll::Ready(Err(closed()));
+        }
+        if this.buffer.len() >= CHUNK_SIZE {
+            ready!(this.poll_send(cx))?;
+        }
+        this.buffer.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.sender.is_some() {
+            ready!(this.poll_send(cx))?;
+            // 送信側をドロップして、チャネルを閉じる
+            this.finished.store(true, Ordering::Release);
+            this.sender = None;
+        }
+        this.poll_upload(cx)
+    }
+}
+
+/// `GcsWriter`に書き込んだデータを返すソース
+///
+/// `GcsWriter`を`shutdown`せずにドロップした場合は、エラーを返してアップロードを中止する。
+#[derive(Debug)]
+pub struct WriterSource {
+    receiver: Receiver<Bytes>,
+    finished: Arc<AtomicBool>,
+}
+
+impl StreamingSource for WriterSource {
+    type Error = io::Error;
+
+    async fn next(&mut self) -> Option<Result<Bytes, Self::Error>> {
+        match self.receiver.recv().await {
+            Some(b) => Some(Ok(b)),
+            None if self.finished.load(Ordering::Acquire) => None,
+            None => Some(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the writer was dropped before shutdown",
+            ))),
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the upload is no longer running")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GcsWriter;
+    use crate::FakeStorage;
+    use gcs::client::Storage;
+    use google_cloud_gax::error::rpc::Code;
+    use google_cloud_storage as gcs;
+    use tokio::io::AsyncWriteExt as _;
+
+    const BUCKET: &str = "projects/_/buckets/my-bucket";
+
+    #[tokio::test]
+    async fn test_copy() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        // チャネルの容量を超えるデータ
+        let contents = (0..1_000_000u32)
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut writer = GcsWriter::new(&client, BUCKET, "my-object");
+        tokio::io::copy(&mut contents.as_slice(), &mut writer).await?;
+        writer.shutdown().await?;
+        let object = writer.object().cloned().expect("the upload has completed");
+        assert_eq!(object.size, contents.len() as i64);
+        assert_eq!(fake.contents(BUCKET, "my-object").unwrap(), contents);
+
+        // `shutdown`の後は書き込めない
+        let err = writer.write_all(b"more").await.expect_err("already closed");
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_with_upload() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        let mut writer = GcsWriter::with_upload(|source| {
+            client
+                .write_object(BUCKET, "data.csv", source)
+                .set_content_type("text/csv")
+                .send_buffered()
+        });
+        writer.write_all(b"id,name\n").await?;
+        writer.write_all(b"1,a\n").await?;
+        let object = writer.finish().await?;
+        assert_eq!(object.content_type, "text/csv");
+        assert_eq!(fake.contents(BUCKET, "data.csv").unwrap(), "id,name\n1,a\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_error() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        fake.insert(BUCKET, "my-object", "old");
+        let client = Storage::from_stub(fake.clone());
+
+        // オブジェクトが存在するため、アップロードは失敗する
+        let mut writer = GcsWriter::with_upload(|source| {
+            client
+                .write_object(BUCKET, "my-object", source)
+                .set_if_generation_match(0)
+                .send_buffered()
+        });
+        let _ = writer.write_all(b"new").await;
+        let err = writer
+            .finish()
+            .await
+            .expect_err("the object already exists");
+        assert_eq!(err.status().map(|s| s.code), Some(Code::FailedPrecondition));
+        assert_eq!(fake.contents(BUCKET, "my-object").unwrap(), "old");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drop() -> anyhow::Result<()> {
+        let fake = FakeStorage::new();
+        let client = Storage::from_stub(fake.clone());
+
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let mut writer = GcsWriter::with_upload(|source| {
+            let upload = client
+                .write_object(BUCKET, "my-object", source)
+                .send_buffered();
+            async move {
+                let result = upload.await;
+                let _ = sender.send(result.is_ok());
+                result
+            }
+        });
+        writer.write_all(b"partial").await?;
+        writer.flush().await?;
+        drop(writer);
+
+        // `shutdown`せずにドロップした場合は、途中までのデータでオブジェクトを作成しない
+        assert!(!receiver.await?);
+        assert!(fake.object(BUCKET, "my-object").is_none());
+        Ok(())
+    }
+}